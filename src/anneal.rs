@@ -1,49 +1,595 @@
-use argmin::{core::CostFunction, solver::simulatedannealing::Anneal};
-use rand::{rng, Rng};
+use std::{borrow::Cow, fs::{self, File}, hash::{Hash, Hasher}, io::{BufReader, BufWriter}, path::{Path, PathBuf}, sync::{atomic::{AtomicU64, Ordering}, Arc}};
 
-use crate::key_converter::{index_pair, InputKey, IN_KEYS_COUNT, OUT_KEYS_COUNT, OUT_KEY_PAIR_PROBS};
+use argmin::{core::{checkpointing::{Checkpoint, CheckpointingFrequency}, CostFunction, IterState, State}, solver::simulatedannealing::{Anneal, SimulatedAnnealing}};
+use rand::{Rng, RngCore};
+use rand_xoshiro::{rand_core::{RngCore as _, SeedableRng as _}, Xoshiro256PlusPlus};
+use serde::{Deserialize, Serialize};
 
-// Only the first OUT_KEYS_COUNT are actually used for the cost
-type State = [InputKey; IN_KEYS_COUNT];
+use crate::{constraints::Constraints, corpus::{BigramTable, MacroCandidate}, cost::{CostModel, SameFingerModel}, key_converter::{index_pair, InputKey, KeyGeometry, OutKey, OUT_KEYS, OUT_KEYS_COUNT}};
 
-pub struct Problem;
+pub type Solver = SimulatedAnnealing<f64, Xoshiro256PlusPlus>;
+pub type AnnealState = IterState<Layout, (), (), (), (), f64>;
 
-impl CostFunction for Problem {
-    type Param = State;
+// The same moves for a seed on every platform, which SmallRng doesn't promise
+// rand_xoshiro is on the rand_core argmin uses, so this gives it the newer Rng
+struct StepRng(Xoshiro256PlusPlus);
 
-    type Output = f64;
+impl RngCore for StepRng {
+    fn next_u32(&mut self) -> u32 {
+        self.0.next_u32()
+    }
 
-    // Could get some crazy speed up if cached this then only update the parts changed in Anneal (like order of magnitude)
-    fn cost(&self, param: &Self::Param) -> Result<Self::Output, argmin::core::Error> {
+    fn next_u64(&mut self) -> u64 {
+        self.0.next_u64()
+    }
+
+    fn fill_bytes(&mut self, dest: &mut [u8]) {
+        self.0.fill_bytes(dest)
+    }
+}
+
+// FNV-1a, unlike DefaultHasher it stays the same between Rust versions so checkpoints keep matching
+struct Fingerprint(u64);
+
+impl Fingerprint {
+    fn new() -> Self {
+        Self(0xCBF2_9CE4_8422_2325)
+    }
+}
+
+impl Hasher for Fingerprint {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 = (self.0 ^ *byte as u64).wrapping_mul(0x100_0000_01B3);
+        }
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Layout {
+    // Only the first OUT_KEYS_COUNT are actually used for the cost
+    pub keys: Vec<InputKey>,
+    // Output index -> the tap that types it instead of its chord, empty until anneal first moves a tap
+    #[serde(default)]
+    pub taps: Vec<Option<usize>>,
+
+    // Filled in by anneal so the cost doesn't have to be recomputed from scratch every iteration
+    cost: Option<f64>
+}
+
+impl Layout {
+    pub fn new(keys: Vec<InputKey>) -> Self {
+        Self { keys, taps: vec![], cost: None }
+    }
+}
+
+// A move that breaks a constraint is undone and another one is tried, this many times before giving up on it
+const MAX_ATTEMPTS: u32 = 100;
+// How often a move swaps two whole left keys instead of two chords, only when there are groups to keep together
+const SWAP_LEFT_RATE: f64 = 0.1;
+// How often a move gives an output a tap instead of swapping chords, only when there are taps
+const TAP_MOVE_RATE: f64 = 0.2;
+
+// A run of characters that could get its own chord instead of being typed out
+struct Macro {
+    text: String,
+    // Indices into OUT_KEYS
+    keys: Vec<usize>,
+    rate: f64
+}
+
+pub struct Problem {
+    table: BigramTable,
+    constraints: Constraints,
+    model: Box<dyn CostModel + Send + Sync>,
+    // Only there when the model has a skip cost
+    skips: Option<Vec<f64>>,
+    // These get the slots right after the outputs
+    macros: Vec<Macro>,
+    // Every left key and then every right key on its own
+    tap_keys: Vec<InputKey>,
+    // Added to each tap for waiting out the cutoff before it types anything
+    tap_wait: f64,
+
+    seed: u64,
+    // How many times anneal has been called, each call gets its own rng from this and the seed
+    //  so a resumed run makes the same moves as one that was never stopped
+    step: Arc<AtomicU64>
+}
+
+impl Problem {
+    pub fn new(table: BigramTable, seed: u64) -> Self {
+        Self { table, constraints: Constraints::default(), model: Box::new(SameFingerModel), skips: None, macros: vec![], tap_keys: vec![], tap_wait: 0.0, seed, step: Arc::new(AtomicU64::new(0)) }
+    }
+
+    // The starting layout has to already meet them (Constraints::initial_layout)
+    pub fn with_constraints(mut self, constraints: Constraints) -> Self {
+        self.constraints = constraints;
+        self
+    }
+
+    pub fn with_model(mut self, model: impl CostModel + Send + Sync + 'static) -> Self {
+        self.skips = model.uses_skips().then(|| self.table.skips());
+        self.model = Box::new(model);
+        self
+    }
+
+    // Candidates with a character that can't be typed are left out
+    pub fn with_macros(mut self, candidates: &[MacroCandidate]) -> Self {
+        self.macros = candidates.iter().filter_map(|candidate| {
+            let keys = candidate.text.chars().map(|character| OutKey::from_text(character)?.index()).collect::<Option<Vec<usize>>>()?;
+            (keys.len() > 1).then(|| Macro { text: candidate.text.clone(), keys, rate: candidate.rate })
+        }).collect();
+        self
+    }
+
+    // Lets outputs be typed by a single key that had nothing to pair with (the tap layer)
+    pub fn with_taps(mut self, geometry: &KeyGeometry, wait: f64) -> Self {
+        self.tap_keys = (0..geometry.left.len()).map(|index| InputKey::tap(geometry, true, index))
+            .chain((0..geometry.right.len()).map(|index| InputKey::tap(geometry, false, index)))
+            .collect();
+        self.tap_wait = wait;
+        self
+    }
+
+    pub fn macro_count(&self) -> usize {
+        self.macros.len()
+    }
+
+    // Seeded from the same seed as the problem so the acceptance rolls are reproducible as well
+    pub fn solver(&self, initial_temperature: f64) -> Result<Solver, argmin::core::Error> {
+        SimulatedAnnealing::new_with_rng(initial_temperature, Xoshiro256PlusPlus::seed_from_u64(self.seed))
+    }
+
+    // Initial is the layout the run starts from, a checkpoint is only picked up by a run with the same inputs
+    pub fn checkpoint(&self, path: impl Into<PathBuf>, every: u64, max_iters: u64, initial: &[InputKey]) -> FileCheckpoint {
+        FileCheckpoint { path: path.into(), every, max_iters, seed: self.seed, fingerprint: self.fingerprint(initial), step: self.step.clone() }
+    }
+
+    // The geometry and the constraints show up in the starting layout, and the table and the model in its cost
+    fn fingerprint(&self, initial: &[InputKey]) -> u64 {
+        let mut hasher = Fingerprint::new();
+        for key in initial {
+            key.name().hash(&mut hasher);
+        }
+
+        self.constraints.hash(&mut hasher);
+        for item in self.macros.iter() {
+            item.text.hash(&mut hasher);
+            item.rate.to_bits().hash(&mut hasher);
+        }
+
+        self.tap_keys.len().hash(&mut hasher);
+        self.tap_wait.to_bits().hash(&mut hasher);
+        self.full_cost(&Layout::new(initial.to_vec())).to_bits().hash(&mut hasher);
+
+        hasher.finish()
+    }
+
+    // The keys with the taps in place of the chords of the outputs that have one, the costs are all worked out on these
+    fn effective<'a>(&self, layout: &'a Layout) -> Cow<'a, [InputKey]> {
+        if layout.taps.iter().all(Option::is_none) {
+            return Cow::Borrowed(&layout.keys);
+        }
+
+        let mut keys = layout.keys.clone();
+        for (index, tap) in layout.taps.iter().enumerate() {
+            if let Some(tap) = tap {
+                keys[index] = self.tap_keys[*tap];
+            }
+        }
+
+        Cow::Owned(keys)
+    }
+
+    // The skip part is still only about two slots so the partial costs work the same with it
+    fn pair_cost(&self, keys: &[InputKey], prev: usize, curr: usize) -> f64 {
+        let wait = if keys[curr].is_tap() { self.tap_wait } else { 0.0 };
+        let mut cost = (self.model.pair_cost(&keys[prev], &keys[curr]) + wait) * self.table.get(prev, curr);
+        if let Some(skips) = &self.skips {
+            cost += self.model.skip_cost(&keys[prev], &keys[curr]) * skips[index_pair(prev, curr)];
+        }
+
+        cost
+    }
+
+    pub fn full_cost(&self, layout: &Layout) -> f64 {
+        self.keys_cost(&self.effective(layout))
+    }
+
+    // Layout::cost drifts from being kept up to date with deltas, so separate runs are compared on their full cost
+    // Gives the cost of each one along with the cheapest
+    pub fn best_of(&self, layouts: Vec<Layout>) -> Option<(Vec<f64>, Layout)> {
+        let costs: Vec<f64> = layouts.iter().map(|layout| self.full_cost(layout)).collect();
+        let best = (0..costs.len()).min_by(|first, second| costs[*first].total_cmp(&costs[*second]))?;
+
+        Some((costs, layouts.into_iter().nth(best)?))
+    }
+
+    fn keys_cost(&self, keys: &[InputKey]) -> f64 {
+        let mut cost: f64 = 0.0;
+        for prev in 0..OUT_KEYS_COUNT {
+            for curr in 0..OUT_KEYS_COUNT {
+                cost += self.pair_cost(keys, prev, curr);
+            }
+        }
+
+        cost + self.macro_cost(keys)
+    }
+
+    // How much the macro on the chord in its slot saves over typing it out, when it does save anything
+    // Typing the characters of it still counts in the table, so this takes them back out
+    fn macro_saving(&self, keys: &[InputKey], index: usize) -> f64 {
+        let item = &self.macros[index];
+        let typed = keys[item.keys[0]].cost() + item.keys.windows(2).map(|pair| self.model.pair_cost(&keys[pair[0]], &keys[pair[1]])).sum::<f64>();
+
+        item.rate * (typed - keys[OUT_KEYS_COUNT + index].cost()).max(0.0)
+    }
+
+    // Only a few short macros so this is always done in full
+    fn macro_cost(&self, keys: &[InputKey]) -> f64 {
+        -(0..self.macros.len()).map(|index| self.macro_saving(keys, index)).sum::<f64>()
+    }
+
+    // The macros worth having a chord for and the chords they got
+    pub fn useful_macros(&self, layout: &Layout) -> Vec<(String, InputKey)> {
+        let keys = self.effective(layout);
+        (0..self.macros.len())
+            .filter(|index| self.macro_saving(&keys, *index) > 0.0 && !self.constraints.forbids(&keys[OUT_KEYS_COUNT + index]))
+            .map(|index| (self.macros[index].text.clone(), keys[OUT_KEYS_COUNT + index]))
+            .collect()
+    }
+
+    // The key of each tap that got an output and the output
+    pub fn taps(&self, layout: &Layout) -> Vec<(char, OutKey)> {
+        layout.taps.iter().enumerate()
+            .filter_map(|(index, tap)| tap.map(|tap| (self.tap_keys[tap].name().chars().next().unwrap_or_default(), OUT_KEYS[index])))
+            .collect()
+    }
+
+    // The part of the cost from every pair that has at least one of the touched indices in it
+    fn partial_cost(&self, keys: &[InputKey], touched: &[usize]) -> f64 {
         let mut cost: f64 = 0.0;
-        for prev_index in 0..OUT_KEYS_COUNT {
-            let prev = &param[prev_index];
-            for curr_index in 0..OUT_KEYS_COUNT {
-                cost += param[curr_index].get_cost(prev) * OUT_KEY_PAIR_PROBS[index_pair(prev_index, curr_index)]
+        for &prev in touched {
+            for curr in 0..OUT_KEYS_COUNT {
+                cost += self.pair_cost(keys, prev, curr);
+            }
+        }
+
+        for prev in 0..OUT_KEYS_COUNT {
+            if touched.contains(&prev) {
+                continue;
+            }
+
+            for &curr in touched {
+                cost += self.pair_cost(keys, prev, curr);
             }
         }
 
-        Ok(cost)
+        cost
+    }
+}
+
+impl Problem {
+    fn swap(keys: &mut [InputKey], first: usize, second: usize) -> Vec<usize> {
+        keys.swap(first, second);
+        vec![first, second]
+    }
+
+    // Every chord on the first left key trades places with the one on the second left key with the same right key
+    fn swap_left(keys: &mut [InputKey], first: usize, second: usize) -> Vec<usize> {
+        if first == second {
+            return vec![];
+        }
+
+        // Found before swapping anything so a chord that was already moved isn't moved back
+        let pairs: Vec<(usize, usize)> = (0..keys.len())
+            .filter(|slot| keys[*slot].left == first)
+            .filter_map(|slot| keys.iter().position(|key| key.compare(second, keys[slot].right)).map(|other| (slot, other)))
+            .collect();
+
+        let mut moved = vec![];
+        for (slot, other) in pairs {
+            keys.swap(slot, other);
+            moved.push(slot);
+            moved.push(other);
+        }
+
+        moved
+    }
+}
+
+impl CostFunction for Problem {
+    type Param = Layout;
+
+    type Output = f64;
+
+    fn cost(&self, param: &Self::Param) -> Result<Self::Output, argmin::core::Error> {
+        Ok(param.cost.unwrap_or_else(|| self.full_cost(param)))
     }
 }
 
 impl Anneal for Problem {
-    type Param = State;
+    type Param = Layout;
 
-    type Output = State;
+    type Output = Layout;
 
     type Float = f64;
 
     fn anneal(&self, param: &Self::Param, extent: Self::Float) -> Result<Self::Output, argmin::core::Error> {
-        let mut out: State = *param;
+        let mut out = param.clone();
+        let mut touched = vec![];
 
-        let mut rng = rng();
+        let step = self.step.fetch_add(1, Ordering::Relaxed);
+        let mut rng = StepRng(Xoshiro256PlusPlus::seed_from_u64(self.seed ^ step.wrapping_mul(0x9E37_79B9_7F4A_7C15)));
         // Lazy ceilling
         for _i in 0..((extent + 1.0) as u64) {
-            out.swap(rng.random_range(0..IN_KEYS_COUNT), rng.random_range(0..IN_KEYS_COUNT));
+            if !self.tap_keys.is_empty() && rng.random_bool(TAP_MOVE_RATE) {
+                if out.taps.is_empty() {
+                    out.taps = vec![None; OUT_KEYS_COUNT];
+                }
+
+                // The output takes the tap from whichever output had it and gives that one its own (if any), one past the last tap takes it off
+                let output = rng.random_range(0..OUT_KEYS_COUNT);
+                let tap = rng.random_range(0..=self.tap_keys.len());
+                let tap = (tap < self.tap_keys.len()).then_some(tap);
+
+                // The holder already had a tap so it is allowed one
+                if !self.constraints.allows_tap(output) {
+                    continue;
+                }
+
+                let holder = out.taps.iter().position(|other| tap.is_some() && *other == tap);
+
+                match holder {
+                    Some(holder) => out.taps.swap(output, holder),
+                    None => out.taps[output] = tap
+                }
+
+                for index in [Some(output), holder].into_iter().flatten() {
+                    if !touched.contains(&index) {
+                        touched.push(index);
+                    }
+                }
+
+                continue;
+            }
+
+            for _attempt in 0..MAX_ATTEMPTS {
+                let swap_left = self.constraints.has_groups() && rng.random_bool(SWAP_LEFT_RATE);
+                let (first, second) = if swap_left {
+                    (rng.random_range(0..self.constraints.left_count()), rng.random_range(0..self.constraints.left_count()))
+                } else {
+                    // Swapping two chords that are both unused does nothing, and with same hand chords most of them are
+                    (rng.random_range(0..(OUT_KEYS_COUNT + self.macros.len())), rng.random_range(0..out.keys.len()))
+                };
+
+                // Both moves undo themselves when done twice
+                let moved = if swap_left { Self::swap_left(&mut out.keys, first, second) } else { Self::swap(&mut out.keys, first, second) };
+                let macro_slots = OUT_KEYS_COUNT..(OUT_KEYS_COUNT + self.macros.len());
+                let forbidden_macro = moved.iter().any(|slot| macro_slots.contains(slot) && self.constraints.forbids(&out.keys[*slot]));
+                if forbidden_macro || !self.constraints.allows(&out.keys, &moved) {
+                    if swap_left { Self::swap_left(&mut out.keys, first, second) } else { Self::swap(&mut out.keys, first, second) };
+                    continue;
+                }
+
+                for index in moved {
+                    if index < OUT_KEYS_COUNT && !touched.contains(&index) {
+                        touched.push(index);
+                    }
+                }
+
+                break;
+            }
         }
 
+        // Each touched index costs about two rows worth of pairs, so past half of them a full pass is cheaper
+        // The deltas drift a little from the full sum over long runs, but not enough to matter for acceptance
+        let (before, after) = (self.effective(param), self.effective(&out));
+        out.cost = match param.cost {
+            Some(cost) if touched.len() * 2 < OUT_KEYS_COUNT =>
+                Some(cost - self.partial_cost(&before, &touched) + self.partial_cost(&after, &touched) - self.macro_cost(&before) + self.macro_cost(&after)),
+            _ => Some(self.keys_cost(&after))
+        };
+
         Ok(out)
     }
 }
 
+// Saves the solver (temperature and its rng) and the state (iteration, current and best layouts) with the seed and what it was run on
+pub struct FileCheckpoint {
+    path: PathBuf,
+    every: u64,
+    // The saved state has the max iters of the run that saved it, this lets a resumed run go longer
+    max_iters: u64,
+
+    seed: u64,
+    fingerprint: u64,
+    step: Arc<AtomicU64>
+}
+
+// The seed is written first so it can be read without knowing the rest
+pub fn checkpoint_seed(path: impl AsRef<Path>) -> Result<u64, argmin::core::Error> {
+    Ok(bincode::deserialize_from(BufReader::new(File::open(path)?))?)
+}
+
+impl Checkpoint<Solver, AnnealState> for FileCheckpoint {
+    fn save(&self, solver: &Solver, state: &AnnealState) -> Result<(), argmin::core::Error> {
+        // Written next to it and then renamed so getting killed mid write doesn't lose the old one
+        let temp = self.path.with_extension("tmp");
+        bincode::serialize_into(BufWriter::new(File::create(&temp)?), &(self.seed, self.fingerprint, solver, state))?;
+        fs::rename(temp, &self.path)?;
+
+        Ok(())
+    }
+
+    fn load(&self) -> Result<Option<(Solver, AnnealState)>, argmin::core::Error> {
+        if !self.path.exists() {
+            return Ok(None);
+        }
+
+        let (seed, fingerprint, solver, state): (u64, u64, Solver, AnnealState) = bincode::deserialize_from(BufReader::new(File::open(&self.path)?))?;
+        if seed != self.seed {
+            return Err(argmin::core::Error::msg(format!("Checkpoint was made with seed {seed} not {}", self.seed)));
+        }
+
+        // Its layouts could be for another geometry or number of macros, which anneal would index past
+        if fingerprint != self.fingerprint {
+            return Err(argmin::core::Error::msg("Checkpoint was made with different geometry, table, constraints, cost, macros or taps"));
+        }
+
+        // Anneal is called once per iteration
+        self.step.store(state.get_iter(), Ordering::Relaxed);
+
+        Ok(Some((solver, state.max_iters(self.max_iters))))
+    }
+
+    fn frequency(&self) -> CheckpointingFrequency {
+        CheckpointingFrequency::Every(self.every)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, process};
+
+    use argmin::core::Executor;
+
+    use super::*;
+    use crate::{cost::{CostWeights, ErgonomicModel}, key_converter::KeyGeometry};
+
+    fn names(layout: &Layout) -> Vec<String> {
+        layout.keys.iter().map(InputKey::name).collect()
+    }
+
+    fn checkpoint_path(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("kybr-{name}-{}.checkpoint", process::id()))
+    }
+
+    // The best layout and its cost
+    fn run(problem: Problem, checkpoint: Option<FileCheckpoint>, initial: &[InputKey], max_iters: u64) -> (Layout, f64) {
+        let solver = problem.solver(10.0).unwrap();
+        let mut runner = Executor::new(problem, solver);
+        if let Some(checkpoint) = checkpoint {
+            runner = runner.checkpointing(checkpoint);
+        }
+
+        let res = runner.configure(|state| state.param(Layout::new(initial.to_vec())).max_iters(max_iters)).run().unwrap();
+        (res.state().get_best_param().unwrap().clone(), res.state().get_best_cost())
+    }
+
+    // The plain same finger cost, and one with every part anneal works out from deltas: skips, macros, taps and one hand chords
+    fn problems(geometry: &KeyGeometry) -> [Problem; 2] {
+        let weights = CostWeights { same_finger: 1.0, travel: 0.3, row_jump: 0.5, same_key: 0.2, same_lead: 0.1, skip_same_finger: 0.5, hand_chord: 0.5, one_hand: 2.0 };
+        let macros = [MacroCandidate { text: "the".to_owned(), rate: 0.01 }, MacroCandidate { text: "ing".to_owned(), rate: 0.005 }];
+
+        [Problem::new(BigramTable::default(), 1), Problem::new(BigramTable::default(), 1).with_model(ErgonomicModel::new(geometry, weights)).with_macros(&macros).with_taps(geometry, 0.5)]
+    }
+
+    #[test]
+    fn incremental_cost_matches_full_cost() {
+        let geometry = KeyGeometry { same_hand: true, ..KeyGeometry::default() };
+
+        for problem in problems(&geometry) {
+            let mut layout = Layout::new(geometry.in_keys());
+            for step in 0..200 {
+                // Big extents touch enough slots to take the full pass, small ones the deltas
+                layout = problem.anneal(&layout, (step % 8) as f64 * 10.0).unwrap();
+
+                let (cost, full) = (layout.cost.unwrap(), problem.full_cost(&layout));
+                assert!((cost - full).abs() <= 1e-9 * full.abs(), "Step {step} has cost {cost} but the full cost is {full}");
+            }
+
+            assert_eq!(layout.taps.iter().any(Option::is_some), !problem.tap_keys.is_empty());
+        }
+    }
+
+    #[test]
+    fn runs_are_compared_on_their_full_cost() {
+        let problem = Problem::new(BigramTable::default(), 1);
+        let keys = KeyGeometry::default().in_keys();
+        let mut layouts = vec![Layout::new(keys.clone()), Layout::new(keys.into_iter().rev().collect())];
+        let full: Vec<f64> = layouts.iter().map(|layout| problem.full_cost(layout)).collect();
+        assert_ne!(full[0], full[1]);
+
+        // Costs that drifted far enough to get the order backwards
+        let (better, worse) = if full[0] < full[1] { (0, 1) } else { (1, 0) };
+        layouts[better].cost = Some(full[worse] + 1.0);
+        layouts[worse].cost = Some(0.0);
+
+        let (costs, best) = problem.best_of(layouts.clone()).unwrap();
+        assert_eq!(costs, full);
+        assert_eq!(names(&best), names(&layouts[better]));
+        assert!(problem.best_of(vec![]).is_none());
+    }
+
+    #[test]
+    fn checkpoints_save_and_load() {
+        let path = checkpoint_path("save");
+        let problem = Problem::new(BigramTable::default(), 3);
+        let mut initial = KeyGeometry::default().in_keys();
+        initial.swap(0, 1);
+
+        let checkpoint = problem.checkpoint(&path, 1, 20, &initial);
+        let state = AnnealState::new().param(Layout::new(initial.clone())).max_iters(10);
+        checkpoint.save(&problem.solver(10.0).unwrap(), &state).unwrap();
+        let (seed, loaded) = (checkpoint_seed(&path), checkpoint.load());
+        fs::remove_file(path).unwrap();
+
+        assert_eq!(seed.unwrap(), 3);
+        let (_, state) = loaded.unwrap().unwrap();
+        assert_eq!(names(state.get_param().unwrap()), names(&Layout::new(initial)));
+        // The max iters come from the run that loads it
+        assert_eq!(state.get_max_iters(), 20);
+    }
+
+    #[test]
+    fn resumed_runs_match_straight_runs() {
+        let path = checkpoint_path("resume");
+        let initial = KeyGeometry::default().in_keys();
+        let straight = run(Problem::new(BigramTable::default(), 7), None, &initial, 100);
+
+        let problem = Problem::new(BigramTable::default(), 7);
+        let checkpoint = problem.checkpoint(&path, 50, 50, &initial);
+        run(problem, Some(checkpoint), &initial, 50);
+        assert!(path.exists());
+
+        let problem = Problem::new(BigramTable::default(), 7);
+        let checkpoint = problem.checkpoint(&path, 50, 100, &initial);
+        let resumed = run(problem, Some(checkpoint), &initial, 100);
+        fs::remove_file(path).unwrap();
+
+        assert_eq!(names(&resumed.0), names(&straight.0));
+        assert_eq!(resumed.1, straight.1);
+        // Another seed goes somewhere else
+        assert_ne!(names(&run(Problem::new(BigramTable::default(), 8), None, &initial, 100).0), names(&straight.0));
+    }
+
+    #[test]
+    fn other_runs_checkpoints_are_rejected() {
+        let path = checkpoint_path("reject");
+        let initial = KeyGeometry::default().in_keys();
+        let problem = Problem::new(BigramTable::default(), 1);
+        let state = AnnealState::new().param(Layout::new(initial.clone()));
+        problem.checkpoint(&path, 1, 10, &initial).save(&problem.solver(10.0).unwrap(), &state).unwrap();
+
+        let macros = [MacroCandidate { text: "the".to_owned(), rate: 0.01 }];
+        let mut moved = initial.clone();
+        moved.swap(0, 1);
+
+        let same = problem.checkpoint(&path, 1, 10, &initial).load();
+        let seed = Problem::new(BigramTable::default(), 2).checkpoint(&path, 1, 10, &initial).load();
+        let macro_problem = Problem::new(BigramTable::default(), 1).with_macros(&macros).checkpoint(&path, 1, 10, &initial).load();
+        let start = problem.checkpoint(&path, 1, 10, &moved).load();
+        fs::remove_file(path).unwrap();
+
+        assert!(same.unwrap().is_some());
+        assert!(seed.is_err());
+        assert!(macro_problem.is_err());
+        assert!(start.is_err());
+    }
+}
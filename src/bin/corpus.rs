@@ -0,0 +1,106 @@
+use std::{env, fs, io, path::Path};
+
+use glob::Pattern;
+use kybr::corpus::{save_macros_path, BigramCounts, NgramCounts, DEFAULT_DELETE_RATE, DEFAULT_NAMED_RATE};
+
+const PATH: &str = "data/corpus.data";
+const MACROS_PATH: &str = "data/macros.toml";
+
+struct Filter {
+    include: Vec<Pattern>,
+    exclude: Vec<Pattern>
+}
+
+impl Filter {
+    fn excludes(&self, path: &Path) -> bool {
+        self.exclude.iter().any(|pattern| pattern.matches_path(path))
+    }
+
+    fn allows(&self, path: &Path) -> bool {
+        (self.include.is_empty() || self.include.iter().any(|pattern| pattern.matches_path(path))) && !self.excludes(path)
+    }
+}
+
+// Patterns are matched against the path relative to the directory given on the command line
+fn walk(root: &Path, dir: &Path, filter: &Filter, add_text: &mut dyn FnMut(&str), files: &mut usize) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let path = entry.path();
+        let relative = path.strip_prefix(root).unwrap_or(&path);
+
+        // Symlinks are skipped so a link back up the tree can't loop forever
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            if !filter.excludes(relative) {
+                walk(root, &path, filter, add_text, files)?;
+            }
+        } else if file_type.is_file() && filter.allows(relative) {
+            // Files that aren't utf8 are most likely binaries
+            if let Ok(text) = fs::read_to_string(&path) {
+                add_text(&text);
+                *files += 1;
+            }
+        }
+    }
+
+    Ok(())
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let mut filter = Filter { include: vec![], exclude: vec![] };
+    let mut delete_rate = DEFAULT_DELETE_RATE;
+    let mut named_rate = DEFAULT_NAMED_RATE;
+    let mut output = PATH.to_owned();
+    let mut macros = 0;
+    let mut macros_output = MACROS_PATH.to_owned();
+    let mut dirs = vec![];
+
+    let mut args_iter = env::args();
+    args_iter.next();
+    while let Some(arg) = args_iter.next() {
+        match arg.as_str() {
+            "--include" => filter.include.push(Pattern::new(&args_iter.next().ok_or("Please specify the include pattern")?)?),
+            "--exclude" => filter.exclude.push(Pattern::new(&args_iter.next().ok_or("Please specify the exclude pattern")?)?),
+            "--delete-rate" => delete_rate = args_iter.next().ok_or("Please specify the delete rate")?.parse()?,
+            // Presses of arrows, function keys, etc. per character typed
+            "--named-rate" => named_rate = args_iter.next().ok_or("Please specify the named key rate")?.parse()?,
+            "--output" => output = args_iter.next().ok_or("Please specify the output path")?,
+            // How many of the most common runs of characters to write out for generate --macros
+            "--macros" => macros = args_iter.next().ok_or("Please specify the number of macros")?.parse()?,
+            "--macros-output" => macros_output = args_iter.next().ok_or("Please specify the macros output path")?,
+            _ => dirs.push(arg)
+        }
+    }
+
+    if dirs.is_empty() {
+        return Err("Please specify at least one directory".into());
+    }
+
+    let mut counts = BigramCounts::new();
+    // Only counted when asked for since it takes a lot more memory
+    let mut ngrams = NgramCounts::new();
+    let mut files = 0;
+    for dir in dirs.iter() {
+        let root = Path::new(dir);
+        walk(root, root, &filter, &mut |text| {
+            counts.add_text(text);
+            if macros > 0 {
+                ngrams.add_text(text);
+            }
+        }, &mut files)?;
+    }
+
+    if counts.pairs() == 0 {
+        return Err("No text found".into());
+    }
+
+    counts.to_table(delete_rate, named_rate)?.save_path(&output)?;
+    println!("Counted {} pairs from {} files into {}", counts.pairs(), files, output);
+
+    if macros > 0 {
+        save_macros_path(&macros_output, &ngrams.candidates(macros, counts.total(delete_rate, named_rate)))?;
+        println!("Wrote {macros} macro candidates to {macros_output}");
+    }
+
+    Ok(())
+}
@@ -1,33 +1,198 @@
-use std::{fs::File, io::Write};
+use std::{env, fs, io, path::Path, thread};
 
-use argmin::{core::{observers::ObserverMode, Executor, State}, solver::simulatedannealing::SimulatedAnnealing};
+use argmin::core::{observers::ObserverMode, Executor, State};
 use argmin_observer_slog::SlogLogger;
-use kybr::key_converter::{IN_KEYS, IN_KEYS_COUNT, OUT_KEYS_COUNT};
-use kybr::anneal::Problem;
+use kybr::bindings::{Bindings, Program};
+use kybr::constraints::Constraints;
+use kybr::corpus::{load_macros_path, BigramTable, MacroCandidate};
+use kybr::cost::{CostWeights, ErgonomicModel};
+use kybr::key_converter::{InputKey, KeyGeometry, OUT_KEYS_COUNT};
+use kybr::anneal::{checkpoint_seed, Layout, Problem};
+use kybr::remapper::{load_params_path, save_layout_path, SpecialChords};
+use rand::Rng;
 
-const PATH: &str = "data/keys.data";
+const PATH: &str = "data/keys.toml";
+const CHECKPOINT_EVERY: u64 = 1000000;
+const MAX_ITERS: u64 = 100000000;
+
+fn checkpoint_path(chain: usize) -> String {
+    format!("data/generate-{chain}.checkpoint")
+}
+
+// Special chords are kept as indices, which only mean the same thing when every chord is made of the same keys
+fn same_keys(first: &KeyGeometry, second: &KeyGeometry) -> bool {
+    let names = |geometry: &KeyGeometry| geometry.in_keys().iter().map(InputKey::name).collect::<Vec<String>>();
+    names(first) == names(second)
+}
+
+fn make_problem(geometry: &KeyGeometry, table: BigramTable, constraints: Constraints, weights: Option<CostWeights>, macros: &[MacroCandidate], tap_wait: Option<f64>, seed: u64) -> Problem {
+    let mut problem = Problem::new(table, seed).with_constraints(constraints).with_macros(macros);
+    if let Some(weights) = weights {
+        problem = problem.with_model(ErgonomicModel::new(geometry, weights));
+    }
+
+    if let Some(wait) = tap_wait {
+        problem = problem.with_taps(geometry, wait);
+    }
+
+    problem
+}
+
+fn run_chain(geometry: &KeyGeometry, problem: Problem, initial: Vec<InputKey>, chain: usize, max_iters: u64) -> Result<Layout, argmin::core::Error> {
+    // Temp goes down to fast (maybe)
+    // Iterations are cheap now that anneal only recomputes the swapped rows and columns
+    let solver = problem.solver(geometry.chord_count() as f64)?;
+    let checkpoint = problem.checkpoint(checkpoint_path(chain), CHECKPOINT_EVERY, max_iters, &initial);
+
+    let mut runner = Executor::new(problem, solver).checkpointing(checkpoint);
+    runner = runner.configure(|state| state.param(Layout::new(initial)).max_iters(max_iters));
+    // Every chain logging at once would be unreadable
+    if chain == 0 {
+        runner = runner.add_observer(SlogLogger::term(), ObserverMode::Every(1000000));
+    }
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    // Temp goes down to fast (maybe) and also it would be better not to hard code the max iters
-    let mut runner = Executor::new(Problem, SimulatedAnnealing::new(IN_KEYS_COUNT as f64)?);
-    runner = runner.configure(|state| state.param(IN_KEYS).max_iters(10000000));
-    runner = runner.add_observer(SlogLogger::term(), ObserverMode::Every(100000));
     let res = runner.run()?;
     let state = res.state();
 
-    let params = match state.get_best_param() {
-        Some(params) => params,
-        None => return Err("No solution".into())
+    match state.get_best_param() {
+        Some(params) => Ok(params.clone()),
+        None => Err(argmin::core::Error::msg("No solution"))
+    }
+}
+
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let mut geometry = KeyGeometry::default();
+    let mut table = BigramTable::default();
+    let mut constraints_path = None;
+    let mut weights = None;
+    let mut macros = vec![];
+    let mut tap_wait = None;
+    let mut seed = None;
+    let mut resume = false;
+    let mut chains = 1;
+    let mut max_iters = MAX_ITERS;
+    let mut layout_path = PATH.to_owned();
+
+    let mut args_iter = env::args();
+    args_iter.next();
+    while let Some(arg) = args_iter.next() {
+        match arg.as_str() {
+            // For boards that chord on other keys
+            "--geometry" => geometry = KeyGeometry::load_path(args_iter.next().ok_or("Please specify the geometry path")?)?,
+            // A table from the corpus binary to optimize for something other than the compiled in code
+            "--table" => table = BigramTable::load_path(args_iter.next().ok_or("Please specify the table path")?)?,
+            // Pinned characters, forbidden chords and characters that have to share a left key
+            "--constraints" => constraints_path = Some(args_iter.next().ok_or("Please specify the constraints path")?),
+            // Weights for the ergonomic cost model, without it only same finger presses cost extra
+            "--cost" => weights = Some(CostWeights::load_path(args_iter.next().ok_or("Please specify the cost path")?)?),
+            // Candidates from the corpus binary, the ones that save more than they cost get chords
+            "--macros" => macros = load_macros_path(args_iter.next().ok_or("Please specify the macros path")?)?,
+            // Lets single keys type outputs when nothing pairs with them, the cost added to each for waiting out the cutoff
+            "--taps" => tap_wait = Some(args_iter.next().ok_or("Please specify the tap wait cost")?.parse()?),
+            "--seed" => seed = Some(args_iter.next().ok_or("Please specify the seed")?.parse()?),
+            // Continue from the last checkpoints instead of starting over
+            "--resume" => resume = true,
+            // Independent runs on their own threads, single runs land in noticeably different minima
+            "--chains" => chains = args_iter.next().ok_or("Please specify the number of chains")?.parse()?,
+            "--iters" => max_iters = args_iter.next().ok_or("Please specify the number of iterations")?.parse()?,
+            // The layout to keep the special chords of and write over
+            "--layout" => layout_path = args_iter.next().ok_or("Please specify the layout path")?,
+            _ => return Err(format!("Unknown argument {arg}").into())
+        }
+    }
+
+    if chains == 0 {
+        return Err("Please specify at least one chain".into());
+    }
+
+    // Only the toml format has room for the geometry and special chords
+    if Path::new(&layout_path).extension().is_none_or(|extension| extension != "toml") {
+        return Err(format!("Layout path {layout_path} isn't a toml file").into());
+    }
+
+    // Loaded after the arguments since they depend on the geometry
+    let mut constraints = match constraints_path {
+        Some(path) => Constraints::load_path(path, &geometry)?,
+        None => Constraints::default()
+    };
+
+    // Modifier and macro chords (and taps) in the layout being replaced stay where they are, as long as it used the same keys
+    let mut special = match load_params_path(&layout_path) {
+        Ok((old, _, special)) if same_keys(&old, &geometry) => special,
+        _ => SpecialChords::default()
+    };
+    constraints.forbid(&special.chords())?;
+    // Chords bound to control actions go over the layout, so nothing is put on them (there are no outputs yet for them to clash with)
+    for program in Program::ALL {
+        constraints.forbid(&Bindings::load_default(program, &geometry, &[], &special)?.chords.keys().copied().collect::<Vec<_>>())?;
+    }
+
+    // Each candidate needs a chord that isn't used for anything else
+    macros.retain(|candidate| !special.macros.values().any(|text| *text == candidate.text));
+    macros.truncate(geometry.chord_count().saturating_sub(OUT_KEYS_COUNT + special.chords().len()));
+
+    let initial = constraints.initial_layout(&geometry)?;
+
+    let seed = match seed {
+        Some(seed) => seed,
+        None if resume => checkpoint_seed(checkpoint_path(0))?,
+        None => rand::rng().random()
     };
 
-    let mut file = File::create(PATH)?;
-    for (i, key) in params.iter().enumerate() {
-        if i == OUT_KEYS_COUNT {
-            break;
+    // The executor picks up any checkpoint it finds so old ones have to go when starting fresh
+    if !resume {
+        for chain in 0..chains {
+            if let Err(err) = fs::remove_file(checkpoint_path(chain)) {
+                if err.kind() != io::ErrorKind::NotFound {
+                    return Err(err.into());
+                }
+            }
         }
+    }
+
+    println!("Seed {seed}");
 
-        file.write_all(&key.as_bytes())?;
+    // Chain n uses seed + n so the first chain is the same as a single chain run with that seed
+    let results = thread::scope(|scope| {
+        let handles: Vec<_> = (0..chains).map(|chain| {
+            let (geometry, table, constraints, weights, macros, initial) = (&geometry, table.clone(), constraints.clone(), weights.clone(), &macros, initial.clone());
+            scope.spawn(move || {
+                let problem = make_problem(geometry, table, constraints, weights, macros, tap_wait, seed.wrapping_add(chain as u64));
+                run_chain(geometry, problem, initial, chain, max_iters)
+            })
+        }).collect();
+
+        handles.into_iter().map(|handle| handle.join().expect("Chain panicked")).collect::<Result<Vec<_>, _>>()
+    })?;
+
+    let problem = make_problem(&geometry, table, constraints, weights, &macros, tap_wait, seed);
+    let (costs, params) = problem.best_of(results).ok_or("No solution")?;
+
+    for (chain, cost) in costs.iter().enumerate() {
+        println!("Chain {chain} (seed {}): {cost}", seed.wrapping_add(chain as u64));
+    }
+
+    let mean = costs.iter().sum::<f64>() / costs.len() as f64;
+    let deviation = (costs.iter().map(|cost| (cost - mean).powi(2)).sum::<f64>() / costs.len() as f64).sqrt();
+    let (best, worst) = (costs.iter().cloned().fold(f64::MAX, f64::min), costs.iter().cloned().fold(f64::MIN, f64::max));
+    println!("Best {best} mean {mean} worst {worst} deviation {deviation}");
+
+    let useful = problem.useful_macros(&params);
+    if !macros.is_empty() {
+        println!("{} of {} macros were worth a chord", useful.len(), macros.len());
     }
 
+    for (text, key) in useful {
+        special.macros.insert((key.left, key.right), text);
+    }
+
+    // Without --taps the old ones are kept like the other special outputs
+    if tap_wait.is_some() {
+        special.taps = problem.taps(&params).into_iter().collect();
+        println!("{} outputs got a tap", special.taps.len());
+    }
+
+    save_layout_path(&layout_path, &geometry, &params.keys, &special)?;
+
     Ok(())
 }
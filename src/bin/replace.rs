@@ -1,143 +1,432 @@
-use std::{env, fs::File, io::Read, panic, process::{exit, Command}, sync::Arc, time::Duration};
+use std::{collections::BTreeMap, env, io, path::Path, process::Command, time::Duration};
 
-use kybr::{key_converter::{InputKey, IN_KEYS_COUNT, LEFT_KEYS, OUT_KEYS, RIGHT_KEYS}, keyboard::{BoardState, HIDReader, HIDWriter, CHAR_TO_KEYCODE, CHAR_TO_SHIFTED}, remapper::Remapper};
+use kybr::{bindings::{Action, Bindings, Program}, discovery::{keyboards, DeviceConfig}, key_converter::{NamedKey, OutKey}, keyboard::{modifier_bit, now, BoardState, Event, HIDReader, HIDWriter, Modifiers, MultiReader, Rollover, CHAR_TO_KEYCODE, CHAR_TO_MEDIA, CHAR_TO_SHIFTED, LED_NAMES}, remapper::{load_params_path, ChordPolicy, Fallback, Output, Remapper}};
 
-const PATH: &str = "data/keys.data";
+const PATH: &str = "data/keys.toml";
+const DEVICES_PATH: &str = "data/devices.toml";
+const CUTOFF: Duration = Duration::from_millis(200);
+// How often to look for keyboards that are missing
+const RECONNECT_POLL: Duration = Duration::from_millis(500);
 
-fn pass_through(reader: &mut HIDReader, writer: &mut HIDWriter) -> Result<(), Box<dyn std::error::Error>> {
-    let mut board = BoardState::CLEAR;
+// Shows the chord in the layer that is on, along with the name of the layer
+fn display_hint(remapper: &Remapper, character: char) {
+    // The physical keys that have a named key to go with them
+    let output = match character {
+        '⇧' => OutKey::Named(NamedKey::Up),
+        '⇩' => OutKey::Named(NamedKey::Down),
+        '⇦' => OutKey::Named(NamedKey::Left),
+        '⇨' => OutKey::Named(NamedKey::Right),
+        '\x1B' => OutKey::Named(NamedKey::Escape),
+        character => OutKey::Char(character)
+    };
 
-    loop {
-        if let Ok(res) = reader.read_valid() {
-            if res.character == '\x7F' && res.down {
-                return Ok(());
-            }
+    if let Some(key) = remapper.chord_for(output) {
+        Command::new("notify-send")
+            .arg(key.name())
+            .arg(remapper.layer().map_or("", |layer| &layer.name))
+            .arg("-t")
+            .arg("1000")
+            .arg("-e")
+            .output()
+            .expect("Failed to notify-send");
+    }
+}
+
+// What the keys do right now
+enum Mode {
+    Chording,
+    // Every key goes to the virtual keyboard as it is, along with what is held
+    PassThrough(BoardState),
+    // The next key (or shift and then a key) shows the chord for it
+    Hint { shifted: bool },
+    // The keyboards aren't grabbed, so they type as if this wasn't running
+    Paused
+}
+
+// Everything a reload reads again
+struct Options {
+    device: Option<String>,
+    layout_path: String,
+    devices_path: Option<String>,
+    bindings_path: Option<String>,
+    policy: ChordPolicy,
+    fallback: Fallback,
+    mode_led: u8
+}
+
+impl Options {
+    fn load_config(&self) -> io::Result<DeviceConfig> {
+        match &self.devices_path {
+            Some(path) => DeviceConfig::load_path(path),
+            None if Path::new(DEVICES_PATH).exists() => DeviceConfig::load_path(DEVICES_PATH),
+            None => Ok(DeviceConfig::default())
+        }
+    }
+
+    // The bindings come with the remapper since their chords are for its geometry
+    fn load_remapper(&self) -> io::Result<(Remapper, Bindings)> {
+        let (geometry, params, special) = load_params_path(&self.layout_path)?;
+        let bindings = match &self.bindings_path {
+            Some(path) => Bindings::load_path(path, Program::Replace, &geometry, &params, &special)?,
+            None => Bindings::load_default(Program::Replace, &geometry, &params, &special)?
+        };
+
+        let remapper = Remapper::new(geometry, params, CUTOFF).with_policy(self.policy).with_special(special).with_fallback(self.fallback).with_actions(bindings.chords.clone());
+        Ok((remapper, bindings))
+    }
+}
+
+struct Daemon {
+    options: Options,
+    config: DeviceConfig,
+    reader: MultiReader,
+    writer: HIDWriter,
+    remapper: Remapper,
+    bindings: Bindings,
+    modifiers: Modifiers,
+    mode: Mode,
+    // When the missing keyboards are looked for next
+    reconnect: Option<Duration>,
+    running: bool
+}
+
+impl Daemon {
+    fn complete(&self) -> bool {
+        self.reader.ids().len() == if self.options.device.is_some() { 1 } else { self.config.patterns().len() }
+    }
+
+    fn open(&self, path: impl AsRef<Path>) -> io::Result<HIDReader> {
+        let mut hid = HIDReader::open_path(path)?;
+        // The real keyboard only goes to the remapper while this is running
+        if !matches!(self.mode, Mode::Paused) {
+            hid.grab()?;
+        }
+
+        Ok(hid)
+    }
 
-            if let Some(keycode) =  CHAR_TO_KEYCODE.get(&res.character) {
-                if res.down {
-                    // If the push fails then do nothing
-                    if !board.push_key(*keycode) {
+    // Opens and grabs whichever keyboards are missing, without waiting for any
+    // One that can't be used yet (udev still setting permissions, grabbed by something else) is tried again on the next poll
+    fn attach(&mut self) -> io::Result<()> {
+        // A device given by number has nothing to match it by so it is only looked for by path
+        if let Some(id) = &self.options.device {
+            if self.reader.is_empty() {
+                match self.open(format!("/dev/input/event{id}")) {
+                    Ok(hid) => {
+                        self.reader.add(hid, 0, BTreeMap::new());
+                        self.writer.sync_leds();
+                    },
+                    Err(err) if err.kind() == io::ErrorKind::NotFound => {},
+                    Err(err) => println!("Couldn't use /dev/input/event{id}: {err}")
+                }
+            }
+        } else {
+            for (index, device) in self.config.find_missing(&self.reader.ids(), &self.reader.paths())? {
+                let hid = match self.open(&device.path) {
+                    Ok(hid) => hid,
+                    Err(err) => {
+                        println!("Couldn't use {device}: {err}");
                         continue;
                     }
-                } else {
-                    board.pop_key(*keycode);
-                }
+                };
+                println!("Using {device}");
 
-                writer.push_state(&board)?;
+                self.reader.add(hid, index, self.config.patterns()[index].remap.clone());
+                // Its LEDs are whatever they were before it was grabbed
+                self.writer.sync_leds();
             }
         }
+
+        self.reconnect = (!self.complete()).then(|| now() + RECONNECT_POLL);
+        Ok(())
     }
-}
 
-fn display_hint(reader: &mut HIDReader, params: &[InputKey; IN_KEYS_COUNT]) {
-    if let Ok(res) = reader.read_valid_down() {
-        let character = if res.character == '\x0E' {
-            if let Ok(res) = reader.read_valid() {
-                if let Some(res) = CHAR_TO_SHIFTED.get(&res.character) {
-                    res.to_owned()
-                } else {
-                    return;
-                }
-            } else {
-                return;
+    // The layout and the keyboards to use are read again, the virtual keyboard stays as it is
+    fn reload(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        let (config, (remapper, bindings)) = match (self.options.load_config(), self.options.load_remapper()) {
+            (Ok(config), Ok(remapper)) => (config, remapper),
+            // Keep going with what was there before
+            (Err(err), _) | (_, Err(err)) => {
+                println!("Reload failed: {err}");
+                return Ok(());
             }
-        } else {
-            res.character
         };
 
+        self.leave_pass_through()?;
+        self.config = config;
+        self.remapper = remapper;
+        self.bindings = bindings;
+        self.modifiers = Modifiers::default();
+        self.writer.push_mods(0)?;
 
-        if let Some(index) = OUT_KEYS.iter().position(|value| *value == character) {
-            let key = params[index];
+        // Remaps and patterns may have changed, so every keyboard is found again
+        self.reader.clear();
+        self.attach()?;
+        // A reload while paused grabs the keyboards again
+        self.writer.indicate(self.options.mode_led);
+        println!("Reloaded");
 
-            Command::new("notify-send")
-                .arg(format!("{}:{}", LEFT_KEYS[key.left], RIGHT_KEYS[key.right]))
-                .arg("-t")
-                .arg("1000")
-                .arg("-e")
-                .output()
-                .expect("Failed to notify-send");
-        }
+        Ok(())
     }
-}
 
-fn run(keyboard_id: &str, hid_id: &str, params: &[InputKey; IN_KEYS_COUNT]) -> Result<(), Box<dyn std::error::Error>> {
-    Command::new("xinput")
-        .arg("float")
-        .arg(keyboard_id)
-        .output()
-        .expect("Failed to disable");
+    fn action(&mut self, action: Action) -> Result<(), Box<dyn std::error::Error>> {
+        match action {
+            Action::PassThrough => {
+                if let Mode::PassThrough(_) = self.mode {
+                    return self.leave_pass_through();
+                }
 
-    let mut reader = HIDReader::open(hid_id)?;
-    let mut writer = HIDWriter::open()?;
-    let mut remapper = Remapper::new(*params, Duration::from_millis(200));
+                self.writer.indicate(0);
+                self.mode = Mode::PassThrough(BoardState::CLEAR);
+            },
+            Action::Hint => self.mode = Mode::Hint { shifted: false },
+            Action::Quit => self.running = false,
+            Action::Reload => self.reload()?,
+            Action::Pause => {
+                if let Mode::Paused = self.mode {
+                    // Waits for the keys to be let go of before grabbing, including the one that did this
+                    self.reader.set_grabbed(true)?;
+                    self.writer.sync_leds();
+                    self.writer.indicate(self.options.mode_led);
+                    self.mode = Mode::Chording;
 
-    loop {
-        if let Ok(res) = reader.read_valid_down() {
-            if res.character == '\x7F' {
-                pass_through(&mut reader, &mut writer)?;
+                    return Ok(());
+                }
 
-                continue;
+                self.leave_pass_through()?;
+                self.modifiers = Modifiers::default();
+                self.writer.push_mods(0)?;
+                self.reader.set_grabbed(false)?;
+                self.writer.indicate(0);
+                self.mode = Mode::Paused;
             }
+        }
+
+        Ok(())
+    }
+
+    fn leave_pass_through(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        if let Mode::PassThrough(_) = self.mode {
+            // Whatever was still held would stay held
+            self.writer.push_state(&BoardState::CLEAR)?;
+            self.writer.indicate(self.options.mode_led);
+            // Modifiers let go of while passing through were never seen
+            self.modifiers = Modifiers::default();
+        }
+
+        self.mode = Mode::Chording;
+        Ok(())
+    }
+
+    fn deadline(&self) -> Option<Duration> {
+        let chord = match self.mode {
+            Mode::Chording => self.remapper.deadline(),
+            _ => None
+        };
+
+        match (chord, self.reconnect) {
+            (Some(chord), Some(reconnect)) => Some(chord.min(reconnect)),
+            (chord, reconnect) => chord.or(reconnect)
+        }
+    }
+
+    // Returns once it is told to stop, or on any error other than a keyboard going away
+    fn run(&mut self) -> Result<(), Box<dyn std::error::Error>> {
+        self.writer.indicate(self.options.mode_led);
+
+        while self.running {
+            match self.reader.wait(self.deadline())? {
+                Event::Key(res) => self.key(res.character, res.time, res.down)?,
+                Event::Signal(libc::SIGHUP) => self.reload()?,
+                Event::Signal(_) => self.running = false,
+                Event::Unplugged => {
+                    // Nothing is going to let go of the keys that were held on it
+                    if let Mode::PassThrough(board) = &mut self.mode {
+                        *board = BoardState::CLEAR;
+                        self.writer.push_state(board)?;
+                    } else {
+                        self.modifiers.release_held();
+                        self.writer.push_mods(self.modifiers.active())?;
+                        self.writer.push_media(None)?;
+                    }
+
+                    self.remapper.release_all();
 
-            if res.character == '\x07' {
-                display_hint(&mut reader, params);
+                    if self.reader.is_empty() {
+                        println!("Keyboard unplugged, waiting for it to come back");
+                    }
+
+                    self.reconnect = Some(now() + RECONNECT_POLL);
+                },
+                Event::Timeout => {
+                    let now = now();
+                    if let Mode::Chording = self.mode {
+                        for output in self.remapper.expire(now) {
+                            self.output(output)?;
+                        }
+                    }
 
-                continue;
+                    if self.reconnect.is_some_and(|reconnect| now >= reconnect) {
+                        self.attach()?;
+                    }
+                }
             }
+        }
+
+        self.leave_pass_through()?;
+        self.writer.push_mods(0)
+    }
+
+    fn key(&mut self, character: char, time: Duration, down: bool) -> Result<(), Box<dyn std::error::Error>> {
+        match &mut self.mode {
+            Mode::PassThrough(board) => {
+                // Everything else has to get to the host
+                if down && self.bindings.key(character) == Some(Action::PassThrough) {
+                    return self.action(Action::PassThrough);
+                }
+
+                if let Some(key) = CHAR_TO_MEDIA.get(&character) {
+                    self.writer.push_media(down.then_some(*key))?;
+                } else if let Some(keycode) = CHAR_TO_KEYCODE.get(&character) {
+                    if down {
+                        board.push_key(*keycode);
+                    } else {
+                        board.pop_key(*keycode);
+                    }
+
+                    self.writer.push_state(board)?;
+                }
+            },
+            Mode::Hint { shifted } => {
+                if !down {
+                    return Ok(());
+                }
+
+                if character == '\x0E' && !*shifted {
+                    *shifted = true;
+                    return Ok(());
+                }
 
-            let character = remapper.push_key(res.character, res.time);
-            if let Some(character) = character {
-                writer.tap(character)?;
+                let character = if *shifted { CHAR_TO_SHIFTED.get(&character).copied() } else { Some(character) };
+                self.mode = Mode::Chording;
+                if let Some(character) = character {
+                    display_hint(&self.remapper, character);
+                }
+            },
+            Mode::Paused => {
+                if down && self.bindings.key(character) == Some(Action::Pause) {
+                    return self.action(Action::Pause);
+                }
+            },
+            Mode::Chording => {
+                // Held physical modifiers apply to every chord while they are down, before any binding so ctrl+s works
+                if let Some(bit) = modifier_bit(character) {
+                    self.modifiers.hold(bit, down);
+                    return self.writer.push_mods(self.modifiers.active());
+                }
+
+                // Their releases are taken as well so they don't reach anything
+                if let Some(action) = self.bindings.key(character) {
+                    return if down { self.action(action) } else { Ok(()) };
+                }
+
+                // The keyboard's own media keys work the same as without the remapper
+                if let Some(key) = CHAR_TO_MEDIA.get(&character) {
+                    return self.writer.push_media(down.then_some(*key));
+                }
+
+                // Half chords that ran out of time before this key come out first
+                for output in self.remapper.expire(time) {
+                    self.output(output)?;
+                }
+
+                if let Some(output) = self.remapper.push_key(character, time, down) {
+                    self.output(output)?;
+                }
             }
         }
+
+        Ok(())
     }
-}
 
-fn reenable(id: &str, slave_id: &str) {
-    Command::new("xinput")
-        .arg("reattach")
-        .arg(id)
-        .arg(slave_id)
-        .output()
-        .expect("Failed to reenable");
+    fn output(&mut self, output: Output) -> Result<(), Box<dyn std::error::Error>> {
+        match output {
+            Output::Key(key) => {
+                self.writer.tap_with_mods(key, self.modifiers.active())?;
+                if self.modifiers.used() {
+                    self.writer.push_mods(self.modifiers.active())?;
+                }
+            },
+            Output::Modifier(character) => {
+                if let Some(bit) = modifier_bit(character) {
+                    self.modifiers.tap(bit);
+                    self.writer.push_mods(self.modifiers.active())?;
+                }
+            },
+            // Typed as is, the modifiers are for the next key
+            Output::Macro(text) => self.writer.type_text(&text, self.modifiers.active())?,
+            Output::Media(key) => self.writer.tap_media(key)?,
+            Output::Action(action) => self.action(action)?
+        }
+
+        Ok(())
+    }
 }
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     sudo::escalate_if_needed()?;
 
+    let mut options = Options { device: None, layout_path: PATH.to_owned(), devices_path: None, bindings_path: None, policy: ChordPolicy::default(), fallback: Fallback::default(), mode_led: 0 };
+    let mut list = false;
+    let mut rollover = Rollover::default();
+
     let mut args_iter = env::args();
     args_iter.next();
-    let x_id = Arc::new(args_iter.next().expect("Please specify keyboard id"));
-    let slave_id = Arc::new(args_iter.next().expect("Please specify slave keyboard id"));
-    let hid_id = args_iter.next().expect("Please specify the hid id");
-
-    // Maybe more elegant way to do this (it probably gets optimized by the compiler though)
-    let mut params: [InputKey; IN_KEYS_COUNT] = [InputKey::new(0, 0); IN_KEYS_COUNT];
-    let mut file = File::options().read(true).open(PATH)?;
-    let mut buf: [u8; 2] = [0, 0];
-    for param in params.iter_mut() {
-        if file.read(&mut buf)? != buf.len() {
-            break;
+    while let Some(arg) = args_iter.next() {
+        match arg.as_str() {
+            // The N in /dev/input/eventN, skips looking for one
+            "--device" => options.device = Some(args_iter.next().ok_or("Please specify the device number")?),
+            // A layout other than data/keys.toml, raw .data layouts from before the toml format work too
+            "--layout" => options.layout_path = args_iter.next().ok_or("Please specify the layout path")?,
+            // Which keyboards to use, otherwise data/devices.toml if it exists or else the first keyboard found
+            "--devices" => options.devices_path = Some(args_iter.next().ok_or("Please specify the devices path")?),
+            // Print every keyboard that was found and exit
+            "--list" => list = true,
+            // nkro so fast rolls in pass through don't lose keys, six-key (the default) for anything that only understands six
+            "--rollover" => rollover = args_iter.next().ok_or("Please specify the rollover")?.parse()?,
+            // Lit while chording and off while passing through: num, caps or scroll
+            "--mode-led" => {
+                let name = args_iter.next().ok_or("Please specify the mode LED")?;
+                options.mode_led = LED_NAMES.iter().find(|led| led.0 == name).ok_or(format!("Unknown LED {name}"))?.1;
+            },
+            // Keys and chords for the control actions, otherwise data/bindings.toml if it exists or else the defaults
+            "--bindings" => options.bindings_path = Some(args_iter.next().ok_or("Please specify the bindings path")?),
+            "--policy" => options.policy = args_iter.next().ok_or("Please specify the chord policy")?.parse()?,
+            // What a key that never gets the other half of its chord does: drop (the default) or key to type it as is
+            "--fallback" => options.fallback = args_iter.next().ok_or("Please specify the fallback")?.parse()?,
+            _ => return Err(format!("Unknown argument {arg}").into())
         }
-
-        *param = InputKey::from_bytes(buf);
     }
 
-    {
-        let x_id = x_id.clone();
-        let slave_id = slave_id.clone();
-        panic::set_hook(Box::new(move |_| { reenable(&x_id, &slave_id); } ))
-    }
+    let config = options.load_config()?;
+
+    if list {
+        for keyboard in keyboards(&config.identity)? {
+            println!("{keyboard}");
+        }
 
-    {
-        let x_id = x_id.clone();
-        let slave_id = slave_id.clone();
-        ctrlc::set_handler(move || { reenable(&x_id, &slave_id); exit(0) } )?
+        return Ok(());
     }
 
-    let result = run(&x_id, &hid_id, &params);
+    // Before the writer starts its thread so that thread doesn't get them either
+    // Stopping goes through the loop so the grabs are released and the held keys let go of
+    let reader = MultiReader::new().with_signals(&[libc::SIGINT, libc::SIGTERM, libc::SIGHUP])?;
+    let writer = HIDWriter::open(rollover, &config.identity)?;
+    writer.mirror_leds(reader.grabbed());
 
-    reenable(&x_id, &slave_id);
+    let (remapper, bindings) = options.load_remapper()?;
+    let mut daemon = Daemon { options, config, reader, writer, remapper, bindings, modifiers: Modifiers::default(), mode: Mode::Chording, reconnect: None, running: true };
 
-    result
+    daemon.attach()?;
+    daemon.run()
 }
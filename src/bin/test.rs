@@ -1,25 +1,34 @@
-use std::{fs::File, io::Read, time::Duration};
+use std::{env, time::Duration};
 
 use iced::Task;
-use kybr::key_converter::{InputKey, IN_KEYS_COUNT};
+use kybr::bindings::{Bindings, Program};
 use kybr::gui::App;
+use kybr::remapper::{load_params_path, ChordPolicy, Remapper};
 use rand::Rng;
 
-const PATH: &str = "data/keys.data";
+const PATH: &str = "data/keys.toml";
 
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    // Maybe more elegant way to do this (it probably gets optimized by the compiler though)
-    let mut params: [InputKey; IN_KEYS_COUNT] = [InputKey::new(0, 0); IN_KEYS_COUNT];
-    let mut file = File::options().read(true).open(PATH)?;
-    let mut buf: [u8; 2] = [0, 0];
-    for param in params.iter_mut() {
-        if file.read(&mut buf)? != buf.len() {
-            break;
-        }
+    let mut policy = ChordPolicy::default();
+    let mut layout_path = PATH.to_owned();
 
-        *param = InputKey::from_bytes(buf);
+    let mut args_iter = env::args();
+    args_iter.next();
+    while let Some(arg) = args_iter.next() {
+        match arg.as_str() {
+            // A layout other than data/keys.toml, raw .data layouts from before the toml format work too
+            "--layout" => layout_path = args_iter.next().ok_or("Please specify the layout path")?,
+            "--policy" => policy = args_iter.next().ok_or("Please specify the chord policy")?.parse()?,
+            _ => return Err(format!("Unknown argument {arg}").into())
+        }
     }
 
+    let load = move || {
+        let (geometry, params, special) = load_params_path(&layout_path)?;
+        let bindings = Bindings::load_default(Program::Trainer, &geometry, &params, &special)?;
+        Ok((Remapper::new(geometry, params, Duration::from_millis(200)).with_policy(policy).with_special(special).with_actions(bindings.chords.clone()), bindings))
+    };
+
     let mut rng = rand::rng();
     let mut chars = vec![];
     for char in "abcdefghijklmnopqrstuvwxyz".chars() { /*ABCDEFGHIJKLMNOPQRSTUVWXYZ*/
@@ -32,9 +41,10 @@ fn main() -> Result<(), Box<dyn std::error::Error>> {
     for i in 1..size - 1 {
         chars.swap(i, rng.random_range(i..size) as usize);
     }
+    let app = App::new(Box::new(load), String::from_iter(chars))?;
     iced::application("Tester", App::update, App::view)
         .subscription(App::subscription)
-        .run_with(move || (App::new(params, Duration::from_millis(200), String::from_iter(chars)), Task::none()))?;
+        .run_with(move || (app, Task::none()))?;
 
     Ok(())
 }
@@ -0,0 +1,231 @@
+use std::{collections::BTreeMap, fs, io, path::Path};
+
+use crate::{error::invalid, key_converter::{InputKey, KeyGeometry, OUT_KEYS_COUNT}, remapper::{parse_chord, SpecialChords}};
+
+pub const BINDINGS_PATH: &str = "data/bindings.toml";
+
+// Things replace and the trainer do instead of typing
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Action {
+    // Keys go to the host as they are until it is pressed again
+    PassThrough,
+    // Shows the chord for the next key
+    Hint,
+    Quit,
+    // Reads the layout (and everything else that has a file) again
+    Reload,
+    // Lets go of the keyboards until it is pressed again
+    Pause
+}
+
+impl Action {
+    pub const ALL: [Action; 5] = [Self::PassThrough, Self::Hint, Self::Quit, Self::Reload, Self::Pause];
+
+    // What it is called in bindings files
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::PassThrough => "pass_through",
+            Self::Hint => "hint",
+            Self::Quit => "quit",
+            Self::Reload => "reload",
+            Self::Pause => "pause"
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.iter().find(|action| action.name() == name).copied()
+    }
+}
+
+// The programs with their own section in bindings files
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Program {
+    Replace,
+    Trainer
+}
+
+impl Program {
+    pub const ALL: [Program; 2] = [Self::Replace, Self::Trainer];
+
+    // The section it reads
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Replace => "replace",
+            Self::Trainer => "trainer"
+        }
+    }
+}
+
+// What triggers each action, a key does it as soon as it goes down and a chord when it is typed
+#[derive(Clone)]
+pub struct Bindings {
+    keys: BTreeMap<char, Action>,
+    pub chords: BTreeMap<(usize, usize), Action>
+}
+
+impl Bindings {
+    // Replace keeps every key that would type something, the trainer has no other use for ` and =
+    pub fn default_for(program: Program) -> Self {
+        let keys = match program {
+            Program::Replace => vec![('\x7F', Action::PassThrough), ('⎀', Action::Hint)],
+            Program::Trainer => vec![('=', Action::Hint), ('`', Action::Quit)]
+        };
+        Self { keys: keys.into_iter().collect(), chords: BTreeMap::new() }
+    }
+
+    // [program] -> action name -> keys (one character, like the devices file) and chords ("left:right", like the layout)
+    // Chords need the layout they go with (no params when there isn't one yet), a missing section means no bindings
+    pub fn load_path(path: impl AsRef<Path>, program: Program, geometry: &KeyGeometry, params: &[InputKey], special: &SpecialChords) -> io::Result<Self> {
+        let text = fs::read_to_string(path)?;
+        let mut file: BTreeMap<String, BTreeMap<String, Vec<String>>> = toml::from_str(&text).map_err(|err| invalid(err.to_string()))?;
+        if let Some(section) = file.keys().find(|section| Program::ALL.iter().all(|program| program.name() != section.as_str())) {
+            return Err(invalid(format!("Unknown section {section:?}")));
+        }
+
+        let mut bindings = Self { keys: BTreeMap::new(), chords: BTreeMap::new() };
+        for (name, triggers) in file.remove(program.name()).unwrap_or_default().iter() {
+            let action = Action::from_name(name).ok_or_else(|| invalid(format!("Unknown action {name:?}")))?;
+
+            for trigger in triggers.iter() {
+                let mut chars = trigger.chars();
+                let used = match (chars.next(), chars.next()) {
+                    (Some(key), None) => bindings.keys.insert(key, action).is_some(),
+                    _ => {
+                        let chord = parse_chord(trigger, geometry).ok_or_else(|| invalid(format!("Invalid trigger {trigger:?} for {name:?}")))?;
+                        bindings.chords.insert(chord, action).is_some()
+                    }
+                };
+
+                if used {
+                    return Err(invalid(format!("Trigger {trigger:?} is used more than once")));
+                }
+            }
+        }
+
+        bindings.check(geometry, params, special)?;
+        Ok(bindings)
+    }
+
+    // BINDINGS_PATH if there is one, otherwise the defaults
+    pub fn load_default(program: Program, geometry: &KeyGeometry, params: &[InputKey], special: &SpecialChords) -> io::Result<Self> {
+        if Path::new(BINDINGS_PATH).exists() {
+            return Self::load_path(BINDINGS_PATH, program, geometry, params, special);
+        }
+
+        let bindings = Self::default_for(program);
+        bindings.check(geometry, params, special)?;
+        Ok(bindings)
+    }
+
+    // Keys are taken before the remapper sees them and chords go over the layout, so neither can be something the layout uses
+    fn check(&self, geometry: &KeyGeometry, params: &[InputKey], special: &SpecialChords) -> io::Result<()> {
+        if let Some(key) = self.keys.keys().find(|key| geometry.left_index(**key).is_some() || geometry.right_index(**key).is_some()) {
+            return Err(invalid(format!("Key {key:?} is used for chords")));
+        }
+
+        let outputs = &params[..params.len().min(OUT_KEYS_COUNT)];
+        if let Some((left, right)) = self.chords.keys().find(|(left, right)| special.contains(*left, *right) || outputs.iter().any(|key| key.compare(*left, *right))) {
+            return Err(invalid(format!("Chord {:?} is used by the layout", InputKey::new(geometry, *left, *right).name())));
+        }
+
+        // Chords aren't read while passing through or paused, only a key can end them
+        for action in [Action::PassThrough, Action::Pause] {
+            if self.chords.values().any(|value| *value == action) && !self.keys.values().any(|value| *value == action) {
+                return Err(invalid(format!("{} needs a key to come back", action.name())));
+            }
+        }
+
+        Ok(())
+    }
+
+    pub fn key(&self, key: char) -> Option<Action> {
+        self.keys.get(&key).copied()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, process};
+
+    use super::*;
+
+    // Against every output in chord order, name keeps the temp files of tests running at the same time apart
+    fn load(name: &str, text: &str, program: Program, special: &SpecialChords) -> io::Result<Bindings> {
+        let geometry = KeyGeometry::default();
+        let params = geometry.in_keys();
+
+        let path = env::temp_dir().join(format!("kybr-bindings-{name}-{}.toml", process::id()));
+        fs::write(&path, text).unwrap();
+        let bindings = Bindings::load_path(&path, program, &geometry, &params, special);
+        fs::remove_file(path).unwrap();
+
+        bindings
+    }
+
+    // A chord no output has
+    fn spare() -> InputKey {
+        KeyGeometry::default().in_keys()[OUT_KEYS_COUNT]
+    }
+
+    #[test]
+    fn bindings_file_loads() {
+        let (geometry, params, special) = crate::remapper::load_params_path("data/keys.toml").unwrap();
+        for program in Program::ALL {
+            assert!(Bindings::load_path(BINDINGS_PATH, program, &geometry, &params, &special).is_ok());
+        }
+    }
+
+    #[test]
+    fn each_program_reads_its_section() {
+        let text = "[replace]\nhint = [\"⎀\"]\n[trainer]\nquit = [\"`\"]";
+        let special = SpecialChords::default();
+
+        let replace = load("replace", text, Program::Replace, &special).unwrap();
+        assert_eq!(replace.key('⎀'), Some(Action::Hint));
+        assert_eq!(replace.key('`'), None);
+        assert_eq!(load("trainer", text, Program::Trainer, &special).unwrap().key('`'), Some(Action::Quit));
+
+        // No section is no bindings, an unknown one is a mistake
+        assert!(load("missing", "[trainer]\nquit = [\"`\"]", Program::Replace, &special).unwrap().keys.is_empty());
+        assert!(load("unknown", "[generate]\nquit = [\"`\"]", Program::Replace, &special).is_err());
+    }
+
+    #[test]
+    fn bad_triggers_are_rejected() {
+        let special = SpecialChords::default();
+
+        assert!(load("action", "[replace]\njump = [\"⎀\"]", Program::Replace, &special).is_err());
+        assert!(load("twice", "[replace]\nhint = [\"⎀\"]\nquit = [\"⎀\"]", Program::Replace, &special).is_err());
+        assert!(load("chord", "[replace]\nhint = [\"q:q\"]", Program::Replace, &special).is_err());
+        // Chording keys never get past the bindings
+        assert!(load("key", "[replace]\nhint = [\"a\"]", Program::Replace, &special).is_err());
+    }
+
+    #[test]
+    fn chords_are_parsed_with_the_geometry() {
+        let text = format!("[replace]\nreload = [\"{}\"]", spare().name());
+        let bindings = load("parsed", &text, Program::Replace, &SpecialChords::default()).unwrap();
+
+        assert_eq!(bindings.chords.into_iter().collect::<Vec<_>>(), [((spare().left, spare().right), Action::Reload)]);
+    }
+
+    #[test]
+    fn chords_the_layout_uses_are_rejected() {
+        let output = KeyGeometry::default().in_keys()[0];
+        assert!(load("output", &format!("[replace]\nreload = [\"{}\"]", output.name()), Program::Replace, &SpecialChords::default()).is_err());
+
+        let mut special = SpecialChords::default();
+        special.macros.insert((spare().left, spare().right), "the".to_owned());
+        assert!(load("special", &format!("[replace]\nreload = [\"{}\"]", spare().name()), Program::Replace, &special).is_err());
+    }
+
+    #[test]
+    fn pause_needs_a_key() {
+        let special = SpecialChords::default();
+        let chord = spare().name();
+
+        assert!(load("pause", &format!("[replace]\npause = [\"{chord}\"]"), Program::Replace, &special).is_err());
+        assert!(load("pass", &format!("[replace]\npass_through = [\"{chord}\"]"), Program::Replace, &special).is_err());
+        assert!(load("both", &format!("[replace]\npause = [\"{chord}\", \"\\u001B\"]"), Program::Replace, &special).is_ok());
+    }
+}
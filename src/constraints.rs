@@ -0,0 +1,260 @@
+use std::{collections::BTreeMap, fs, io, path::Path};
+
+use serde::Deserialize;
+
+use crate::{error::invalid, key_converter::{InputKey, KeyGeometry, OUT_KEYS, OUT_KEYS_COUNT}, remapper::{parse_chord, parse_output}};
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct ConstraintsFile {
+    // Chords that should never be given an output
+    forbidden: Vec<String>,
+    // Each group of characters has to be on the same left key
+    same_left: Vec<Vec<String>>,
+    // Output character -> "left:right"
+    pinned: BTreeMap<String, String>
+}
+
+// Everything is stored as indices into OUT_KEYS and the geometry
+#[derive(Clone, Default, Hash)]
+pub struct Constraints {
+    left_count: usize,
+
+    pinned: Vec<(usize, usize, usize)>,
+    forbidden: Vec<(usize, usize)>,
+    same_left: Vec<Vec<usize>>
+}
+
+impl Constraints {
+    pub fn load_path(path: impl AsRef<Path>, geometry: &KeyGeometry) -> io::Result<Self> {
+        let text = fs::read_to_string(path)?;
+        let file: ConstraintsFile = toml::from_str(&text).map_err(|err| invalid(err.to_string()))?;
+
+        let character = |output: &String| parse_output(output).ok_or_else(|| invalid(format!("Unknown output {output:?}")));
+        let chord = |chord: &String| parse_chord(chord, geometry).ok_or_else(|| invalid(format!("Invalid chord {chord:?}")));
+
+        let mut constraints = Self { left_count: geometry.left.len(), ..Self::default() };
+        for value in file.forbidden.iter() {
+            constraints.forbidden.push(chord(value)?);
+        }
+
+        for (output, value) in file.pinned.iter() {
+            let (left, right) = chord(value)?;
+            if constraints.forbidden.contains(&(left, right)) {
+                return Err(invalid(format!("Pinned chord {value:?} is forbidden")));
+            }
+
+            if constraints.pinned.iter().any(|pin| pin.1 == left && pin.2 == right) {
+                return Err(invalid(format!("Chord {value:?} is pinned more than once")));
+            }
+
+            constraints.pinned.push((character(output)?, left, right));
+        }
+
+        for group in file.same_left.iter() {
+            let group = group.iter().map(character).collect::<io::Result<Vec<usize>>>()?;
+
+            let mut lefts = group.iter().filter_map(|index| constraints.pin(*index)).map(|pin| pin.0);
+            if let Some(left) = lefts.next() {
+                if lefts.any(|other| other != left) {
+                    return Err(invalid("Characters that share a left key are pinned to different left keys".to_owned()));
+                }
+            }
+
+            constraints.same_left.push(group);
+        }
+
+        Ok(constraints)
+    }
+
+    // For chords that are already used by something other than an output
+    pub fn forbid(&mut self, chords: &[(usize, usize)]) -> io::Result<()> {
+        for &(left, right) in chords {
+            if self.pinned.iter().any(|pin| pin.1 == left && pin.2 == right) {
+                return Err(invalid("A pinned chord is already used for something else".to_owned()));
+            }
+
+            self.forbidden.push((left, right));
+        }
+
+        Ok(())
+    }
+
+    fn pin(&self, index: usize) -> Option<(usize, usize)> {
+        self.pinned.iter().find(|pin| pin.0 == index).map(|pin| (pin.1, pin.2))
+    }
+
+    pub fn forbids(&self, key: &InputKey) -> bool {
+        self.forbidden.iter().any(|(left, right)| key.compare(*left, *right))
+    }
+
+    // A tap would take pinned and grouped outputs off the chords they are held to
+    pub fn allows_tap(&self, index: usize) -> bool {
+        self.pin(index).is_none() && !self.same_left.iter().any(|group| group.contains(&index))
+    }
+
+    pub fn has_groups(&self) -> bool {
+        !self.same_left.is_empty()
+    }
+
+    pub fn left_count(&self) -> usize {
+        self.left_count
+    }
+
+    // Only the slots that just changed have to be looked at since everything else was already allowed
+    pub fn allows(&self, keys: &[InputKey], moved: &[usize]) -> bool {
+        for &slot in moved {
+            if slot >= OUT_KEYS_COUNT {
+                continue;
+            }
+
+            let key = &keys[slot];
+            if self.forbids(key) {
+                return false;
+            }
+
+            if let Some((left, right)) = self.pin(slot) {
+                if !key.compare(left, right) {
+                    return false;
+                }
+            }
+
+            for group in self.same_left.iter().filter(|group| group.contains(&slot)) {
+                if group.iter().any(|index| keys[*index].left != key.left) {
+                    return false;
+                }
+            }
+        }
+
+        true
+    }
+
+    // Something that already meets every constraint for the optimizer to start from
+    pub fn initial_layout(&self, geometry: &KeyGeometry) -> io::Result<Vec<InputKey>> {
+        let mut free = geometry.in_keys();
+        free.retain(|key| !self.forbids(key));
+
+        let mut slots: Vec<Option<InputKey>> = vec![None; OUT_KEYS_COUNT];
+
+        for &(index, left, right) in self.pinned.iter() {
+            let position = free.iter().position(|key| key.compare(left, right)).ok_or_else(|| invalid(format!("Pinned chord for {} isn't available", OUT_KEYS[index])))?;
+            slots[index] = Some(free.remove(position));
+        }
+
+        for group in self.same_left.iter() {
+            // Pinned or placed by an earlier group
+            let placed_left = group.iter().find_map(|index| slots[*index]).map(|key| key.left);
+            let needed = group.iter().filter(|index| slots[**index].is_none()).count();
+
+            let left = match placed_left {
+                Some(left) => left,
+                // Any left key with room for the whole group, the optimizer can move it from there
+                None => (0..geometry.left.len()).find(|left| free.iter().filter(|key| key.left == *left).count() >= needed)
+                    .ok_or_else(|| invalid("No left key has enough chords for a group".to_owned()))?
+            };
+
+            for &index in group.iter() {
+                if slots[index].is_some() {
+                    continue;
+                }
+
+                let position = free.iter().position(|key| key.left == left).ok_or_else(|| invalid(format!("Not enough chords on the left key for {}", OUT_KEYS[index])))?;
+                slots[index] = Some(free.remove(position));
+            }
+        }
+
+        while let Some(index) = slots.iter().position(Option::is_none) {
+            if free.is_empty() {
+                return Err(invalid("Not enough chords left after the constraints".to_owned()));
+            }
+
+            slots[index] = Some(free.remove(0));
+        }
+
+        let mut keys: Vec<InputKey> = slots.into_iter().flatten().collect();
+        let (unused, forbidden): (Vec<InputKey>, Vec<InputKey>) = geometry.in_keys().into_iter()
+            .filter(|key| !keys.iter().any(|used| used.compare(key.left, key.right)))
+            .partition(|key| !self.forbids(key));
+        // The chords right after the outputs are where the macros go, so the forbidden ones are kept out of the way
+        keys.extend(unused);
+        keys.extend(forbidden);
+
+        Ok(keys)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, process};
+
+    use super::*;
+
+    const EXAMPLE_PATH: &str = "data/constraints.toml";
+
+    fn load_text(text: &str, geometry: &KeyGeometry) -> io::Result<Constraints> {
+        let path = env::temp_dir().join(format!("kybr-constraints-{}.toml", process::id()));
+        fs::write(&path, text)?;
+        let constraints = Constraints::load_path(&path, geometry);
+        fs::remove_file(path)?;
+
+        constraints
+    }
+
+    fn output(name: &str) -> usize {
+        parse_output(name).unwrap()
+    }
+
+    #[test]
+    fn initial_layout_meets_the_constraints() {
+        let geometry = KeyGeometry::default();
+        let constraints = Constraints::load_path(EXAMPLE_PATH, &geometry).unwrap();
+        let keys = constraints.initial_layout(&geometry).unwrap();
+
+        assert_eq!(keys.len(), geometry.chord_count());
+        assert!(constraints.allows(&keys, &(0..OUT_KEYS_COUNT).collect::<Vec<_>>()));
+
+        let (left, right) = parse_chord("d:k", &geometry).unwrap();
+        assert!(keys[output("↲")].compare(left, right));
+        for name in ["x:/", "z:."] {
+            let (left, right) = parse_chord(name, &geometry).unwrap();
+            assert!(!keys[..OUT_KEYS_COUNT].iter().any(|key| key.compare(left, right)));
+        }
+
+        assert!(["a", "s", "d", "f"].iter().all(|name| keys[output(name)].left == keys[output("a")].left));
+    }
+
+    #[test]
+    fn allows_rejects_moves_that_break_them() {
+        let geometry = KeyGeometry::default();
+        let constraints = Constraints::load_path(EXAMPLE_PATH, &geometry).unwrap();
+        let keys = constraints.initial_layout(&geometry).unwrap();
+        let swapped = |first: usize, second: usize| {
+            let mut keys = keys.clone();
+            keys.swap(first, second);
+            constraints.allows(&keys, &[first, second])
+        };
+
+        // Moving a pinned output, splitting a group, and giving an output a forbidden chord
+        assert!(!swapped(output("↲"), output("q")));
+        let outside = (0..OUT_KEYS_COUNT).find(|slot| keys[*slot].left != keys[output("a")].left).unwrap();
+        assert!(!swapped(output("a"), outside));
+        let (left, right) = parse_chord("x:/", &geometry).unwrap();
+        let forbidden = keys.iter().position(|key| key.compare(left, right)).unwrap();
+        assert!(!swapped(output("q"), forbidden));
+
+        // Two outputs on the same left key in a group can trade places
+        let inside = (0..OUT_KEYS_COUNT).find(|slot| *slot != output("a") && constraints.pin(*slot).is_none() && keys[*slot].left == keys[output("a")].left).unwrap();
+        assert!(swapped(output("a"), inside));
+    }
+
+    #[test]
+    fn conflicting_constraints_are_rejected() {
+        let geometry = KeyGeometry::default();
+        assert!(load_text("forbidden = [\"d:k\"]\n[pinned]\n\"↲\" = \"d:k\"\n", &geometry).is_err());
+        assert!(load_text("[pinned]\n\"a\" = \"d:k\"\n\"b\" = \"d:k\"\n", &geometry).is_err());
+        assert!(load_text("same_left = [[\"a\", \"b\"]]\n[pinned]\n\"a\" = \"d:k\"\n\"b\" = \"f:j\"\n", &geometry).is_err());
+
+        let mut constraints = load_text("[pinned]\n\"↲\" = \"d:k\"\n", &geometry).unwrap();
+        assert!(constraints.forbid(&[parse_chord("d:k", &geometry).unwrap()]).is_err());
+    }
+}
@@ -0,0 +1,337 @@
+use std::{collections::HashMap, fs, io, path::Path};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{error::invalid, key_converter::{index_pair, CHAR_KEYS_COUNT, CHAR_PAIR_PROBS, OUT_KEYS_COUNT}};
+
+// What scripts/download.py used
+pub const DEFAULT_DELETE_RATE: f64 = 0.2;
+// Text doesn't say anything about how often the named keys are used so by default the optimizer doesn't care where they go
+pub const DEFAULT_NAMED_RATE: f64 = 0.0;
+
+// Macro candidates are this long at most, longer ones are rare enough not to be worth it
+pub const MAX_MACRO_LENGTH: usize = 8;
+// NgramCounts drops its rarest entries past this many, so big corpora don't use up all the memory
+const MAX_NGRAMS: usize = 1 << 22;
+
+// Backspace never shows up in text so every character counts towards it and then that gets scaled by the delete rate
+const DELETE_INDEX: usize = CHAR_KEYS_COUNT - 1;
+
+pub struct BigramCounts {
+    counts: Vec<u64>,
+    pairs: u64
+}
+
+impl Default for BigramCounts {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// \n -> ↲ \t -> → and the printable characters are in the same order as OUT_KEYS
+fn char_index(character: char) -> Option<usize> {
+    match character {
+        ' '..='~' => Some(character as usize - 30),
+        '\t' => Some(1),
+        '\n' => Some(0),
+        _ => None
+    }
+}
+
+impl BigramCounts {
+    pub fn new() -> Self {
+        Self { counts: vec![0; OUT_KEYS_COUNT * OUT_KEYS_COUNT], pairs: 0 }
+    }
+
+    // Anything that isn't in OUT_KEYS breaks the chain so no pair is counted across it
+    pub fn add_text(&mut self, text: &str) {
+        let mut prev = None;
+        for character in text.chars() {
+            if character == '\r' {
+                continue;
+            }
+
+            let Some(curr) = char_index(character) else {
+                prev = None;
+                continue;
+            };
+
+            if let Some(prev) = prev {
+                self.counts[index_pair(prev, curr)] += 1;
+                self.pairs += 1;
+            }
+
+            self.counts[index_pair(curr, DELETE_INDEX)] += 1;
+            prev = Some(curr);
+        }
+    }
+
+    pub fn pairs(&self) -> u64 {
+        self.pairs
+    }
+
+    // The named keys are treated like backspace with the named rate split evenly between them
+    fn weighted(&self, delete_rate: f64, named_rate: f64) -> Vec<f64> {
+        let mut probs: Vec<f64> = self.counts.iter().map(|count| *count as f64).collect();
+        for prev in 0..OUT_KEYS_COUNT {
+            let characters = probs[index_pair(prev, DELETE_INDEX)];
+            probs[index_pair(prev, DELETE_INDEX)] *= delete_rate;
+
+            for named in CHAR_KEYS_COUNT..OUT_KEYS_COUNT {
+                probs[index_pair(prev, named)] = characters * named_rate / (OUT_KEYS_COUNT - CHAR_KEYS_COUNT) as f64;
+            }
+        }
+
+        probs
+    }
+
+    // What the counts get divided by to make the table, so other counts can be put on the same scale
+    pub fn total(&self, delete_rate: f64, named_rate: f64) -> f64 {
+        self.weighted(delete_rate, named_rate).iter().sum()
+    }
+
+    pub fn to_table(&self, delete_rate: f64, named_rate: f64) -> io::Result<BigramTable> {
+        let mut probs = self.weighted(delete_rate, named_rate);
+
+        let sum: f64 = probs.iter().sum();
+        for prob in probs.iter_mut() {
+            *prob /= sum;
+        }
+
+        BigramTable::new(probs)
+    }
+}
+
+// Every run of characters up to MAX_MACRO_LENGTH long, for finding text worth giving a chord
+#[derive(Default)]
+pub struct NgramCounts {
+    counts: HashMap<String, u64>
+}
+
+impl NgramCounts {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // Same rules as BigramCounts::add_text, nothing is counted across a character that isn't in OUT_KEYS
+    pub fn add_text(&mut self, text: &str) {
+        let mut run: Vec<char> = vec![];
+        for character in text.chars().filter(|character| *character != '\r').chain(['\0']) {
+            if char_index(character).is_some() {
+                run.push(character);
+                continue;
+            }
+
+            for start in 0..run.len() {
+                for end in (start + 2)..=(start + MAX_MACRO_LENGTH).min(run.len()) {
+                    *self.counts.entry(run[start..end].iter().collect()).or_default() += 1;
+                }
+
+                // Newlines and tabs are in OUT_KEYS, so a run can be a whole file
+                if self.counts.len() > MAX_NGRAMS {
+                    self.prune();
+                }
+            }
+
+            run.clear();
+        }
+    }
+
+    // Down to half of MAX_NGRAMS, anything this rare is nowhere near being a candidate
+    fn prune(&mut self) {
+        let mut floor = 1;
+        while self.counts.len() > MAX_NGRAMS / 2 {
+            self.counts.retain(|_, count| *count > floor);
+            floor *= 2;
+        }
+    }
+
+    // The ones that save the most presses, total is from BigramCounts::total so the rates are on the same scale as the table
+    pub fn candidates(&self, count: usize, total: f64) -> Vec<MacroCandidate> {
+        let saved = |(text, count): &(&String, &u64)| **count * (text.chars().count() as u64 - 1);
+
+        let mut counts: Vec<(&String, &u64)> = self.counts.iter().collect();
+        counts.sort_by(|first, second| saved(second).cmp(&saved(first)).then(first.0.cmp(second.0)));
+
+        counts.into_iter().take(count).map(|(text, count)| MacroCandidate { text: text.clone(), rate: *count as f64 / total }).collect()
+    }
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct MacroCandidate {
+    pub text: String,
+    // Same as the probability of a pair in the bigram table
+    pub rate: f64
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct MacrosFile {
+    #[serde(default)]
+    candidate: Vec<MacroCandidate>
+}
+
+pub fn load_macros_path(path: impl AsRef<Path>) -> io::Result<Vec<MacroCandidate>> {
+    let text = fs::read_to_string(path)?;
+    let file: MacrosFile = toml::from_str(&text).map_err(|err| invalid(err.to_string()))?;
+
+    Ok(file.candidate)
+}
+
+pub fn save_macros_path(path: impl AsRef<Path>, candidates: &[MacroCandidate]) -> io::Result<()> {
+    let text = toml::to_string(&MacrosFile { candidate: candidates.to_vec() }).map_err(|err| invalid(err.to_string()))?;
+    fs::write(path, text)
+}
+
+#[derive(Clone)]
+pub struct BigramTable {
+    probs: Vec<f64>
+}
+
+impl Default for BigramTable {
+    fn default() -> Self {
+        Self::embedded()
+    }
+}
+
+impl BigramTable {
+    // Rounding from summing ~10000 floats is fine, anything more means it was never normalized
+    const SUM_TOLERANCE: f64 = 1e-6;
+
+    pub fn new(probs: Vec<f64>) -> io::Result<Self> {
+        if probs.len() != OUT_KEYS_COUNT * OUT_KEYS_COUNT {
+            return Err(invalid(format!("Expected {} probabilities but got {}", OUT_KEYS_COUNT * OUT_KEYS_COUNT, probs.len())));
+        }
+
+        if let Some(index) = probs.iter().position(|prob| !(prob.is_finite() && *prob >= 0.0)) {
+            return Err(invalid(format!("Invalid probability {} at {}", probs[index], index)));
+        }
+
+        let sum: f64 = probs.iter().sum();
+        if (sum - 1.0).abs() > Self::SUM_TOLERANCE {
+            return Err(invalid(format!("Probabilities sum to {sum} instead of 1")));
+        }
+
+        Ok(Self { probs })
+    }
+
+    // The one from data/code.data that gets compiled in
+    pub fn embedded() -> Self {
+        Self { probs: Self::pad(&CHAR_PAIR_PROBS) }
+    }
+
+    // Tables from before the named keys only have the characters, which come first so the rest is just zeros
+    fn pad(probs: &[f64]) -> Vec<f64> {
+        let mut padded = vec![0.0; OUT_KEYS_COUNT * OUT_KEYS_COUNT];
+        for (prev, row) in probs.chunks_exact(CHAR_KEYS_COUNT).enumerate() {
+            padded[index_pair(prev, 0)..index_pair(prev, CHAR_KEYS_COUNT)].copy_from_slice(row);
+        }
+
+        padded
+    }
+
+    // Same layout as numpy's tobytes
+    pub fn load_path(path: impl AsRef<Path>) -> io::Result<Self> {
+        let bytes = fs::read(path)?;
+        if bytes.len() % size_of::<f64>() != 0 {
+            return Err(invalid("Table size isn't a whole number of floats".to_owned()));
+        }
+
+        let probs: Vec<f64> = bytes.chunks_exact(size_of::<f64>()).map(|chunk| f64::from_ne_bytes(chunk.try_into().unwrap())).collect();
+        if probs.len() == CHAR_KEYS_COUNT * CHAR_KEYS_COUNT {
+            return Self::new(Self::pad(&probs));
+        }
+
+        Self::new(probs)
+    }
+
+    pub fn save_path(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        fs::write(path, self.probs.iter().flat_map(|prob| prob.to_ne_bytes()).collect::<Vec<u8>>())
+    }
+
+    pub fn get(&self, prev: usize, curr: usize) -> f64 {
+        self.probs[index_pair(prev, curr)]
+    }
+
+    // How likely first is followed by anything and then third, treating the text as a chain of bigrams
+    pub fn skips(&self) -> Vec<f64> {
+        let totals: Vec<f64> = (0..OUT_KEYS_COUNT).map(|middle| (0..OUT_KEYS_COUNT).map(|next| self.get(middle, next)).sum()).collect();
+
+        let mut skips = vec![0.0; OUT_KEYS_COUNT * OUT_KEYS_COUNT];
+        for first in 0..OUT_KEYS_COUNT {
+            for (middle, total) in totals.iter().enumerate() {
+                let prob = self.get(first, middle);
+                if prob == 0.0 || *total == 0.0 {
+                    continue;
+                }
+
+                for third in 0..OUT_KEYS_COUNT {
+                    skips[index_pair(first, third)] += prob * self.get(middle, third) / total;
+                }
+            }
+        }
+
+        skips
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, process};
+
+    use super::*;
+    use crate::key_converter::{OutKey, OUT_KEYS};
+
+    #[test]
+    fn counts_match_the_download_script() {
+        assert_eq!(char_index('\t'), Some(1));
+        assert_eq!(char_index('\n'), Some(0));
+        assert!((' '..='~').all(|character| OUT_KEYS[char_index(character).unwrap()] == OutKey::Char(character)));
+
+        // The \r is skipped so b goes into the newline, the é breaks the chain so the newline doesn't go into c
+        let mut counts = BigramCounts::new();
+        counts.add_text("a\tb\r\néc");
+        let (a, b, c) = (char_index('a').unwrap(), char_index('b').unwrap(), char_index('c').unwrap());
+
+        assert_eq!(counts.pairs(), 3);
+        assert_eq!(counts.counts[index_pair(a, 1)], 1);
+        assert_eq!(counts.counts[index_pair(1, b)], 1);
+        assert_eq!(counts.counts[index_pair(b, 0)], 1);
+        assert_eq!(counts.counts[index_pair(0, c)], 0);
+
+        // Three pairs plus a backspace for each of the five characters at half weight
+        let table = counts.to_table(0.5, 0.0).unwrap();
+        assert!((table.get(a, 1) - 1.0 / 5.5).abs() < 1e-12);
+        assert!((table.get(a, DELETE_INDEX) - 0.5 / 5.5).abs() < 1e-12);
+    }
+
+    #[test]
+    fn bad_tables_are_rejected() {
+        let uniform = vec![1.0 / (OUT_KEYS_COUNT * OUT_KEYS_COUNT) as f64; OUT_KEYS_COUNT * OUT_KEYS_COUNT];
+        assert!(BigramTable::new(uniform.clone()).is_ok());
+
+        assert!(BigramTable::new(uniform[1..].to_vec()).is_err());
+        for bad in [f64::NAN, f64::INFINITY, -1e-9] {
+            let mut probs = uniform.clone();
+            probs[5] = bad;
+            assert!(BigramTable::new(probs).is_err());
+        }
+
+        // Counts that were never normalized
+        assert!(BigramTable::new(uniform.iter().map(|prob| prob * 2.0).collect()).is_err());
+    }
+
+    #[test]
+    fn character_tables_are_padded() {
+        // A table from before the named keys, like data/code.data
+        let uniform = vec![1.0 / (CHAR_KEYS_COUNT * CHAR_KEYS_COUNT) as f64; CHAR_KEYS_COUNT * CHAR_KEYS_COUNT];
+        let path = env::temp_dir().join(format!("kybr-table-{}.data", process::id()));
+        fs::write(&path, uniform.iter().flat_map(|prob| prob.to_ne_bytes()).collect::<Vec<u8>>()).unwrap();
+        let table = BigramTable::load_path(&path);
+        fs::remove_file(path).unwrap();
+
+        let table = table.unwrap();
+        assert_eq!(table.get(CHAR_KEYS_COUNT - 1, CHAR_KEYS_COUNT - 1), uniform[0]);
+        assert_eq!(table.get(0, CHAR_KEYS_COUNT), 0.0);
+        assert_eq!(table.get(OUT_KEYS_COUNT - 1, 0), 0.0);
+    }
+}
@@ -0,0 +1,301 @@
+use std::{fs, io, path::Path};
+
+use serde::Deserialize;
+
+use crate::{error::invalid, key_converter::{HandKey, InputKey, KeyGeometry}};
+
+pub trait CostModel {
+    // Cost of pressing curr right after prev
+    fn pair_cost(&self, prev: &InputKey, curr: &InputKey) -> f64;
+
+    // Extra cost of pressing third with one chord in between after first
+    fn skip_cost(&self, _first: &InputKey, _third: &InputKey) -> f64 {
+        0.0
+    }
+
+    // The skip table takes a while to build so it is only made when it gets used
+    fn uses_skips(&self) -> bool {
+        false
+    }
+}
+
+// The original cost, just doubled when a finger has to go to a different key
+pub struct SameFingerModel;
+
+impl CostModel for SameFingerModel {
+    fn pair_cost(&self, prev: &InputKey, curr: &InputKey) -> f64 {
+        curr.get_cost(prev)
+    }
+}
+
+// Everything is added on top of the cost of the keys, the defaults are the same as SameFingerModel for chords on both hands.
+//  Same hand chords only get doubled when a finger moves, SameFingerModel doubles them whenever they share a finger with the last chord
+#[derive(Clone, Deserialize)]
+#[serde(default)]
+pub struct CostWeights {
+    // Multiple of the key cost added when a finger has to go to a different key
+    pub same_finger: f64,
+    // Per key width a finger has to move when it goes to a different key
+    pub travel: f64,
+    // For each hand that goes two or more rows between different keys
+    pub row_jump: f64,
+    // For each hand that has to press the same key again
+    pub same_key: f64,
+    // The hand with the easier key lands first, this is added when the same hand leads twice in a row
+    pub same_lead: f64,
+    // For each hand that uses the same finger on a different key for the first and third chord
+    pub skip_same_finger: f64,
+    // For each hand that presses two keys at once, same hand and three key chords are harder to land than two keys
+    pub hand_chord: f64,
+    // For each same hand chord, those only come out once the cutoff is over (taps get theirs from generate --taps)
+    pub one_hand: f64
+}
+
+impl Default for CostWeights {
+    fn default() -> Self {
+        Self { same_finger: 1.0, travel: 0.0, row_jump: 0.0, same_key: 0.0, same_lead: 0.0, skip_same_finger: 0.0, hand_chord: 0.0, one_hand: 0.0 }
+    }
+}
+
+impl CostWeights {
+    pub fn load_path(path: impl AsRef<Path>) -> io::Result<Self> {
+        let text = fs::read_to_string(path)?;
+        let weights: Self = toml::from_str(&text).map_err(|err| invalid(err.to_string()))?;
+
+        // A negative weight would make the annealer look for the worst layouts for that part
+        let values = [
+            ("same_finger", weights.same_finger), ("travel", weights.travel), ("row_jump", weights.row_jump), ("same_key", weights.same_key),
+            ("same_lead", weights.same_lead), ("skip_same_finger", weights.skip_same_finger), ("hand_chord", weights.hand_chord), ("one_hand", weights.one_hand)
+        ];
+        if let Some((name, value)) = values.iter().find(|(_, value)| !(value.is_finite() && *value >= 0.0)) {
+            return Err(invalid(format!("Invalid weight {value} for {name}")));
+        }
+
+        Ok(weights)
+    }
+}
+
+pub struct ErgonomicModel {
+    // The keys of every part of each hand (KeyGeometry::part_keys)
+    left: Vec<Vec<HandKey>>,
+    right: Vec<Vec<HandKey>>,
+    weights: CostWeights
+}
+
+impl ErgonomicModel {
+    pub fn new(geometry: &KeyGeometry, weights: CostWeights) -> Self {
+        let parts = |left: bool| (0..geometry.part_count(left)).map(|part| geometry.part_keys(left, part).into_iter().cloned().collect()).collect();
+
+        Self { left: parts(true), right: parts(false), weights }
+    }
+
+    // Nothing on a side that isn't pressed
+    fn hands(&self, key: &InputKey) -> (&[HandKey], &[HandKey]) {
+        (self.left.get(key.left).map_or(&[], Vec::as_slice), self.right.get(key.right).map_or(&[], Vec::as_slice))
+    }
+
+    fn left_leads(&self, key: &InputKey) -> bool {
+        let cost = |keys: &[HandKey]| keys.iter().map(|key| key.cost).sum::<f64>();
+        match self.hands(key) {
+            (left, right) if !left.is_empty() && !right.is_empty() => cost(left) <= cost(right),
+            (left, _) => !left.is_empty()
+        }
+    }
+}
+
+impl CostModel for ErgonomicModel {
+    fn pair_cost(&self, prev: &InputKey, curr: &InputKey) -> f64 {
+        let (prev_left, prev_right) = self.hands(prev);
+        let (curr_left, curr_right) = self.hands(curr);
+
+        let base: f64 = curr_left.iter().chain(curr_right.iter()).map(|key| key.cost).sum();
+        let mut cost = base;
+        let mut same_finger = false;
+
+        for (from, to) in [(prev_left, curr_left), (prev_right, curr_right)] {
+            // Landing the chord is just as hard when the hand wasn't used before
+            if to.len() > 1 {
+                cost += self.weights.hand_chord;
+            }
+
+            // A hand that isn't used by one of the two has nothing to move
+            if from.is_empty() || to.is_empty() {
+                continue;
+            }
+
+            if from.iter().map(|key| key.key).eq(to.iter().map(|key| key.key)) {
+                cost += self.weights.same_key;
+                continue;
+            }
+
+            // Every finger that goes from one key to another, the keys pressed both times don't move
+            let moves: Vec<(&HandKey, &HandKey)> = from.iter()
+                .flat_map(|from| to.iter().map(move |to| (from, to)))
+                .filter(|(from, to)| from.key != to.key)
+                .collect();
+
+            for (from, to) in moves.iter().filter(|(from, to)| from.finger == to.finger) {
+                same_finger = true;
+                cost += self.weights.travel * from.distance(to);
+            }
+
+            if moves.iter().any(|(from, to)| (from.row - to.row).abs() >= 2.0) {
+                cost += self.weights.row_jump;
+            }
+        }
+
+        if !curr.is_tap() && (curr_left.is_empty() || curr_right.is_empty()) {
+            cost += self.weights.one_hand;
+        }
+
+        // Only once even if both hands do it, like the original
+        if same_finger {
+            cost += base * self.weights.same_finger;
+        }
+
+        if self.left_leads(prev) == self.left_leads(curr) {
+            cost += self.weights.same_lead;
+        }
+
+        cost
+    }
+
+    fn skip_cost(&self, first: &InputKey, third: &InputKey) -> f64 {
+        let (first_left, first_right) = self.hands(first);
+        let (third_left, third_right) = self.hands(third);
+
+        [(first_left, third_left), (first_right, third_right)].iter()
+            .filter(|(from, to)| from.iter().any(|from| to.iter().any(|to| from.key != to.key && from.finger == to.finger)))
+            .count() as f64 * self.weights.skip_same_finger
+    }
+
+    fn uses_skips(&self) -> bool {
+        self.weights.skip_same_finger != 0.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, process};
+
+    use super::*;
+    use crate::remapper::parse_chord;
+
+    fn chord(geometry: &KeyGeometry, chord: &str) -> InputKey {
+        let (left, right) = parse_chord(chord, geometry).unwrap();
+        InputKey::new(geometry, left, right)
+    }
+
+    // What one weight adds to the pair on top of the defaults
+    fn extra(weights: CostWeights, prev: &str, curr: &str) -> f64 {
+        let geometry = KeyGeometry::default();
+        let (prev, curr) = (chord(&geometry, prev), chord(&geometry, curr));
+
+        ErgonomicModel::new(&geometry, weights).pair_cost(&prev, &curr) - ErgonomicModel::new(&geometry, CostWeights::default()).pair_cost(&prev, &curr)
+    }
+
+    #[test]
+    fn default_weights_are_the_same_finger_model() {
+        let geometry = KeyGeometry::default();
+        let model = ErgonomicModel::new(&geometry, CostWeights::default());
+        let keys = geometry.in_keys();
+
+        for prev in keys.iter() {
+            for curr in keys.iter() {
+                assert_eq!(model.pair_cost(prev, curr), SameFingerModel.pair_cost(prev, curr), "{} then {}", prev.name(), curr.name());
+            }
+        }
+    }
+
+    #[test]
+    fn travel_is_per_key_width() {
+        let weights = || CostWeights { travel: 1.0, ..CostWeights::default() };
+        assert_eq!(extra(weights(), "q:j", "a:j"), 1.0);
+        assert_eq!(extra(weights(), "z:j", "q:u"), 3.0);
+        // Other fingers don't travel
+        assert_eq!(extra(weights(), "a:j", "s:j"), 0.0);
+    }
+
+    #[test]
+    fn row_jumps_are_two_rows() {
+        let weights = || CostWeights { row_jump: 1.0, ..CostWeights::default() };
+        assert_eq!(extra(weights(), "q:j", "z:j"), 1.0);
+        assert_eq!(extra(weights(), "q:u", "x:m"), 2.0);
+        assert_eq!(extra(weights(), "q:j", "a:j"), 0.0);
+    }
+
+    #[test]
+    fn same_key_is_per_hand() {
+        let weights = || CostWeights { same_key: 1.0, ..CostWeights::default() };
+        assert_eq!(extra(weights(), "a:j", "a:k"), 1.0);
+        assert_eq!(extra(weights(), "a:j", "a:j"), 2.0);
+        assert_eq!(extra(weights(), "a:j", "s:k"), 0.0);
+    }
+
+    #[test]
+    fn same_lead_is_the_cheaper_hand_twice() {
+        let weights = || CostWeights { same_lead: 1.0, ..CostWeights::default() };
+        // j is cheaper than a and s, f is cheaper than k
+        assert_eq!(extra(weights(), "a:j", "s:j"), 1.0);
+        assert_eq!(extra(weights(), "a:j", "f:k"), 0.0);
+    }
+
+    #[test]
+    fn hand_chords_are_charged_after_any_chord() {
+        let geometry = KeyGeometry { same_hand: true, three_keys: true, ..KeyGeometry::default() };
+        let model = ErgonomicModel::new(&geometry, CostWeights { hand_chord: 1.0, ..CostWeights::default() });
+        let default = ErgonomicModel::new(&geometry, CostWeights::default());
+        // The key costs don't add up exactly, with a weight of 1 this is how many hands got charged
+        let extra = |prev: &str, curr: &str| {
+            let (prev, curr) = (chord(&geometry, prev), chord(&geometry, curr));
+            (model.pair_cost(&prev, &curr) - default.pair_cost(&prev, &curr)).round()
+        };
+
+        assert_eq!(extra("a:j", "qw:"), 1.0);
+        assert_eq!(extra(":jk", "qw:"), 1.0);
+        assert_eq!(extra("qw:", "a:jk"), 1.0);
+        assert_eq!(extra("qw:", "a:j"), 0.0);
+    }
+
+    #[test]
+    fn same_hand_chords_are_only_doubled_when_a_finger_moves() {
+        let geometry = KeyGeometry { same_hand: true, ..KeyGeometry::default() };
+        let model = ErgonomicModel::new(&geometry, CostWeights::default());
+        let (prev, curr) = (chord(&geometry, "q:/"), chord(&geometry, "qw:"));
+        let keys = curr.get_cost(&curr);
+
+        // q stays down and w is on another finger, SameFingerModel still doubles it for sharing q
+        assert_eq!(model.pair_cost(&prev, &curr), keys);
+        assert_eq!(SameFingerModel.pair_cost(&prev, &curr), keys * 2.0);
+        assert_eq!(model.pair_cost(&chord(&geometry, "a:/"), &curr), keys * 2.0);
+    }
+
+    #[test]
+    fn skips_are_per_hand() {
+        let geometry = KeyGeometry::default();
+        let model = ErgonomicModel::new(&geometry, CostWeights { skip_same_finger: 1.0, ..CostWeights::default() });
+        let skip = |first: &str, third: &str| model.skip_cost(&chord(&geometry, first), &chord(&geometry, third));
+
+        assert!(model.uses_skips());
+        assert!(!ErgonomicModel::new(&geometry, CostWeights::default()).uses_skips());
+        assert_eq!(skip("q:j", "a:k"), 1.0);
+        assert_eq!(skip("q:j", "a:h"), 2.0);
+        assert_eq!(skip("q:j", "q:j"), 0.0);
+    }
+
+    #[test]
+    fn bad_weights_are_rejected() {
+        assert!(CostWeights::load_path("data/cost.toml").is_ok());
+
+        let path = env::temp_dir().join(format!("kybr-cost-{}.toml", process::id()));
+        let mut loaded = vec![];
+        for text in ["travel = 0.5", "travel = nan", "row_jump = -1.0", "same_key = inf"] {
+            fs::write(&path, text).unwrap();
+            loaded.push(CostWeights::load_path(&path).map(|weights| weights.travel));
+        }
+        fs::remove_file(path).unwrap();
+
+        assert_eq!(loaded[0].as_ref().unwrap(), &0.5);
+        assert!(loaded[1..].iter().all(Result::is_err));
+    }
+}
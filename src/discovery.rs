@@ -0,0 +1,201 @@
+use std::{collections::BTreeMap, fmt, fs::{self, File}, io, os::{fd::AsRawFd, raw::c_ulong}, path::{Path, PathBuf}};
+
+use glob::Pattern;
+use serde::Deserialize;
+
+use crate::{error::invalid, input::{input_id, ioctl, EV_KEY, KEY_A, KEY_ENTER, KEY_SPACE, KEY_Z, _IOC_READ}, keyboard::{evdev_request, DeviceIdentity, KEY_BYTES}};
+
+const INPUT_DIR: &str = "/dev/input";
+// Longer names are cut off, which is fine for matching
+const NAME_LENGTH: usize = 256;
+
+const EVIOCGID: c_ulong = evdev_request(_IOC_READ, 0x02, size_of::<input_id>());
+const EVIOCGNAME: c_ulong = evdev_request(_IOC_READ, 0x06, NAME_LENGTH);
+const EVIOCGBIT_KEY: c_ulong = evdev_request(_IOC_READ, 0x20 + EV_KEY, KEY_BYTES);
+
+// Anything without these is a mouse, power button, media keys, etc.
+const REQUIRED_KEYS: [u32; 4] = [KEY_A, KEY_Z, KEY_SPACE, KEY_ENTER];
+
+#[derive(Clone)]
+pub struct DeviceInfo {
+    pub path: PathBuf,
+    pub name: String,
+    pub vendor: u16,
+    pub product: u16
+}
+
+impl fmt::Display for DeviceInfo {
+    fn fmt(&self, format: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(format, "{} {:?} ({:04x}:{:04x})", self.path.display(), self.name, self.vendor, self.product)
+    }
+}
+
+impl DeviceInfo {
+    // None for anything that isn't a keyboard
+    pub fn query(path: impl AsRef<Path>) -> io::Result<Option<Self>> {
+        let path = path.as_ref();
+        let file = File::open(path)?;
+        let fd = file.as_raw_fd();
+
+        let mut keys = [0u8; KEY_BYTES];
+        if unsafe { ioctl(fd, EVIOCGBIT_KEY, keys.as_mut_ptr()) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        if !REQUIRED_KEYS.iter().all(|key| keys[*key as usize / 8] & (1 << (key % 8)) != 0) {
+            return Ok(None);
+        }
+
+        let mut id = input_id::default();
+        if unsafe { ioctl(fd, EVIOCGID, &mut id as *mut input_id) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let mut name = [0u8; NAME_LENGTH];
+        if unsafe { ioctl(fd, EVIOCGNAME, name.as_mut_ptr()) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let length = name.iter().position(|byte| *byte == 0).unwrap_or(name.len());
+        let name = String::from_utf8_lossy(&name[..length]).into_owned();
+
+        Ok(Some(Self { path: path.to_owned(), name, vendor: id.vendor, product: id.product }))
+    }
+
+    // The virtual keyboard HIDWriter makes would otherwise be picked up as one to remap
+    // hid-generic adds " Keyboard" to the name unless it already ends with it, so only the start has to match
+    fn is_own(&self, own: &DeviceIdentity) -> bool {
+        self.vendor == own.vendor && self.product == own.product && self.name.starts_with(&own.name)
+    }
+}
+
+// Every keyboard that can be opened other than the virtual one, in event number order
+pub fn keyboards(own: &DeviceIdentity) -> io::Result<Vec<DeviceInfo>> {
+    let mut paths = vec![];
+    for entry in fs::read_dir(INPUT_DIR)? {
+        let path = entry?.path();
+        let number = path.file_name().and_then(|name| name.to_str()).and_then(|name| name.strip_prefix("event")).and_then(|number| number.parse::<u32>().ok());
+        if let Some(number) = number {
+            paths.push((number, path));
+        }
+    }
+
+    paths.sort();
+
+    // Devices that can't be opened (permissions, unplugged while looking) are skipped
+    Ok(paths.into_iter()
+        .filter_map(|(_, path)| DeviceInfo::query(path).ok().flatten())
+        .filter(|device| !device.is_own(own))
+        .collect())
+}
+
+// Reading from a device that was unplugged fails with this
+pub fn is_unplugged(err: &io::Error) -> bool {
+    err.raw_os_error() == Some(libc::ENODEV)
+}
+
+#[derive(Deserialize)]
+struct PatternFile {
+    name: Option<String>,
+    vendor: Option<u16>,
+    product: Option<u16>,
+    // Key -> the key it should be treated as
+    #[serde(default)]
+    remap: BTreeMap<String, String>
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct DevicesFile {
+    keyboard: Vec<PatternFile>,
+    #[serde(rename = "virtual")]
+    identity: DeviceIdentity
+}
+
+// Everything that is set has to match, the name is a glob
+#[derive(Default)]
+pub struct DevicePattern {
+    name: Option<Pattern>,
+    vendor: Option<u16>,
+    product: Option<u16>,
+    pub remap: BTreeMap<char, char>
+}
+
+impl DevicePattern {
+    pub fn matches(&self, device: &DeviceInfo) -> bool {
+        self.name.as_ref().is_none_or(|name| name.matches(&device.name))
+            && self.vendor.is_none_or(|vendor| vendor == device.vendor)
+            && self.product.is_none_or(|product| product == device.product)
+    }
+}
+
+fn parse_key(key: &str) -> io::Result<char> {
+    let mut chars = key.chars();
+    match (chars.next(), chars.next()) {
+        (Some(key), None) => Ok(key),
+        _ => Err(invalid(format!("Invalid key {key:?}")))
+    }
+}
+
+// Each pattern is a keyboard to use, all of them together for boards that show up as more than one device
+pub struct DeviceConfig {
+    patterns: Vec<DevicePattern>,
+    // The virtual keyboard the output goes to
+    pub identity: DeviceIdentity
+}
+
+// No patterns means the first keyboard found
+impl Default for DeviceConfig {
+    fn default() -> Self {
+        Self { patterns: vec![DevicePattern::default()], identity: DeviceIdentity::default() }
+    }
+}
+
+impl DeviceConfig {
+    pub fn load_path(path: impl AsRef<Path>) -> io::Result<Self> {
+        let text = fs::read_to_string(path)?;
+        let file: DevicesFile = toml::from_str(&text).map_err(|err| invalid(err.to_string()))?;
+
+        file.identity.validate()?;
+        if file.keyboard.is_empty() {
+            return Ok(Self { identity: file.identity, ..Self::default() });
+        }
+
+        let mut patterns = vec![];
+        for pattern in file.keyboard {
+            let name = pattern.name.map(|name| Pattern::new(&name)).transpose().map_err(|err| invalid(err.to_string()))?;
+
+            let mut remap = BTreeMap::new();
+            for (from, to) in pattern.remap.iter() {
+                remap.insert(parse_key(from)?, parse_key(to)?);
+            }
+
+            patterns.push(DevicePattern { name, vendor: pattern.vendor, product: pattern.product, remap });
+        }
+
+        Ok(Self { patterns, identity: file.identity })
+    }
+
+    pub fn patterns(&self) -> &[DevicePattern] {
+        &self.patterns
+    }
+
+    // Keyboards for the patterns not in used, skipping the ones already open
+    // Each pattern takes the first keyboard that matches it and no earlier pattern took
+    pub fn find_missing(&self, used: &[usize], used_paths: &[PathBuf]) -> io::Result<Vec<(usize, DeviceInfo)>> {
+        let mut keyboards: Vec<DeviceInfo> = keyboards(&self.identity)?.into_iter().filter(|device| !used_paths.contains(&device.path)).collect();
+
+        let mut found = vec![];
+        for (index, pattern) in self.patterns.iter().enumerate() {
+            if used.contains(&index) {
+                continue;
+            }
+
+            if let Some(position) = keyboards.iter().position(|device| pattern.matches(device)) {
+                found.push((index, keyboards.remove(position)));
+            }
+        }
+
+        Ok(found)
+    }
+}
@@ -0,0 +1,6 @@
+use std::io;
+
+// For files and settings that were read fine but don't make sense
+pub(crate) fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
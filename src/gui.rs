@@ -1,12 +1,19 @@
-use std::{cmp::min, process::exit, time::{Duration, Instant}};
+use std::{cmp::min, io, process::exit, time::Instant};
 
-use iced::{event, keyboard::Key, widget::{column, text, Column}, Alignment::Center, Event, Fill, Subscription};
+use iced::{event, keyboard::{key::Named, Key}, widget::{column, text, Column}, Alignment::Center, Event, Fill, Subscription};
 // use rand::Rng;
 
-use crate::{key_converter::{InputKey, IN_KEYS_COUNT, LEFT_KEYS, OUT_KEYS, RIGHT_KEYS}, remapper::Remapper};
+use crate::{bindings::{Action, Bindings}, key_converter::OutKey, remapper::{Output, Remapper}};
+
+// Makes the remapper and its bindings, again on every reload
+pub type Loader = Box<dyn Fn() -> io::Result<(Remapper, Bindings)>>;
 
 pub struct App {
     remapper: Remapper,
+    bindings: Bindings,
+    load: Loader,
+    // Keys do nothing other than unpause
+    paused: bool,
 
     start: Instant,
 
@@ -23,31 +30,32 @@ pub enum Message {
 }
 
 impl App {
-    pub fn new(params: [InputKey; IN_KEYS_COUNT], cutoff: Duration, target: String) -> Self {
-        Self { remapper: Remapper::new(params, cutoff), start: Instant::now(), target, garbage_index: 0, hinted: false, start_hint: 2/*rand::rng().random_range(0..2)*/ }
+    pub fn new(load: Loader, target: String) -> io::Result<Self> {
+        let (remapper, bindings) = load()?;
+        Ok(Self { remapper, bindings, load, paused: false, start: Instant::now(), target, garbage_index: 0, hinted: false, start_hint: 2/*rand::rng().random_range(0..2)*/ })
     }
 
-    pub fn view(&self) -> Column<Message> {
+    pub fn view(&self) -> Column<'_, Message> {
         if self.target.is_empty() {
             // Cringe
             exit(0)
         }
 
-        let hint = if self.hinted {
-            let char = self.target.chars().nth(self.garbage_index).unwrap();
-            let key = self.remapper.params[OUT_KEYS.iter().position(|value| *value == char).unwrap()];
-            text(format!("{}:{}", LEFT_KEYS[key.left], RIGHT_KEYS[key.right]))
+        // The chord in the layer that is on, same as the hints in replace
+        let char = self.target.chars().nth(self.garbage_index).unwrap();
+        let key = self.remapper.chord_for(OutKey::Char(char)).unwrap();
+        let hint = if self.paused {
+            text("Paused")
+        } else if self.hinted {
+            text(key.name())
+        } else if self.start_hint == 0 {
+            text(format!("{}:", key.left_name()))
+        } else if self.start_hint == 1 {
+            text(format!(":{}", key.right_name()))
         } else {
-            let char = self.target.chars().nth(self.garbage_index).unwrap();
-            let key = self.remapper.params[OUT_KEYS.iter().position(|value| *value == char).unwrap()];
-            if self.start_hint == 0 {
-                text(format!("{}:", LEFT_KEYS[key.left]))
-            } else if self.start_hint == 1 {
-                text(format!(":{}", RIGHT_KEYS[key.right]))
-            } else {
-                text(":")
-            }
+            text(":")
         };
+        let layer = self.remapper.layer().map_or("", |layer| &layer.name);
 
         let mut pad = "".to_owned();
         for _i in 0..self.garbage_index {
@@ -56,49 +64,93 @@ impl App {
 
         column![
             text(self.target[0..min(16, self.target.len())].to_owned() + "\n" + &pad + "^").size(50),
-            hint.size(50)
+            hint.size(50),
+            text(layer).size(25)
         ].width(Fill).align_x(Center)
     }
 
     pub fn update(&mut self, message: Message) {
-        if let Message::Press(Key::Character(chars)) = message {
+        let (key, down) = match &message {
+            Message::Press(key) => (key, true),
+            Message::Release(key) => (key, false)
+        };
+
+        if let Some(action) = key_char(key).and_then(|character| self.bindings.key(character)) {
+            if down {
+                self.action(action);
+            }
+
+            return
+        }
+
+        if self.paused {
+            return
+        }
+
+        let Key::Character(chars) = key else {
+            return
+        };
+
+        if let Some(char) = chars.chars().next() {
             let time = Instant::now() - self.start;
 
-            if let Some(char) = chars.chars().next() {
-                if char == '`' {
-                    // Cringe
-                    exit(0)
-                }
+            // Half chords that ran out of time come out first, there is no timer here so that waits for the next key
+            for output in self.remapper.expire(time) {
+                self.output(output);
+            }
 
-                if char == '=' {
-                    self.hinted = true;
-                    return
-                }
+            if let Some(output) = self.remapper.push_key(char, time, down) {
+                self.output(output);
+            }
+        }
+    }
+
+    fn output(&mut self, output: Output) {
+        if let Output::Action(action) = output {
+            return self.action(action);
+        }
 
-                if let Some(char) = self.remapper.push_key(char, time) {
-                    if char == '←' {
-                        if self.garbage_index > 0 {
-                            self.garbage_index -= 1;
-                            self.target.remove(0);
-                        }
-                    } else if self.garbage_index == 0 && self.target.starts_with(char) {
-                        self.target.remove(0);
-                        self.hinted = false;
-                        /*self.start_hint += 1;
-                        if self.start_hint == 3 {
-                            self.start_hint = 0;
-                        }*/
-                    } else {
-                        self.target.insert(0, char);
-
-                        self.garbage_index += 1;
-                        self.hinted = true;
-                    }
+        // Only characters can be typed into the target
+        if let Some(char) = if let Output::Key(key) = output { key.character() } else { None } {
+            if char == '←' {
+                if self.garbage_index > 0 {
+                    self.garbage_index -= 1;
+                    self.target.remove(0);
                 }
+            } else if self.garbage_index == 0 && self.target.starts_with(char) {
+                self.target.remove(0);
+                self.hinted = false;
+                /*self.start_hint += 1;
+                if self.start_hint == 3 {
+                    self.start_hint = 0;
+                }*/
+            } else {
+                self.target.insert(0, char);
+
+                self.garbage_index += 1;
+                self.hinted = true;
             }
         }
     }
 
+    fn action(&mut self, action: Action) {
+        match action {
+            // Cringe
+            Action::Quit => exit(0),
+            Action::Hint => self.hinted = true,
+            Action::Reload => match (self.load)() {
+                Ok((remapper, bindings)) => {
+                    self.remapper = remapper;
+                    self.bindings = bindings;
+                },
+                Err(err) => println!("Reload failed: {err}")
+            },
+            Action::Pause => self.paused = !self.paused,
+            // Nothing here to pass keys through to
+            Action::PassThrough => {}
+        }
+    }
+
     pub fn subscription(&self) -> Subscription<Message> {
         event::listen_with(|event, _status, _id| match event {
             Event::Keyboard(key_event) => match key_event {
@@ -110,3 +162,16 @@ impl App {
         })
     }
 }
+
+// The characters replace gets for the keys that aren't characters here, so the same bindings work for both
+fn key_char(key: &Key) -> Option<char> {
+    match key {
+        Key::Character(chars) => chars.chars().next(),
+        Key::Named(Named::Delete) => Some('\x7F'),
+        Key::Named(Named::Insert) => Some('⎀'),
+        Key::Named(Named::Control) => Some('\x07'),
+        Key::Named(Named::Shift) => Some('\x0E'),
+        Key::Named(Named::Escape) => Some('\x1B'),
+        _ => None
+    }
+}
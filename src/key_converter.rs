@@ -1,93 +1,508 @@
-use std::fmt;
+use std::{fmt, fs, io, path::Path};
 
 use include_data::include_data;
+use serde::{Deserialize, Serialize};
 
-// Maybe some of this should be defined in the Problem struct
+use crate::error::invalid;
+
+// Key, cost, finger, row and column (rows from the top and columns across the whole board, no stagger)
+type DefaultKey = (char, f64, u32, f64, f64);
 
 // Each hand has four fingers with three keys for each and one extra for the index
-const LEFT_KEYS_COUNT: usize = 4 * 3 + 1;
+const DEFAULT_LEFT: [DefaultKey; 4 * 3 + 1] = [
+    ('q', 2.3, 0, 0.0, 0.0), ('a', 1.3, 0, 1.0, 0.0), ('z', 2.5, 0, 2.0, 0.0),
+    ('w', 1.4, 1, 0.0, 1.0), ('s', 1.2, 1, 1.0, 1.0), ('x', 3.5, 1, 2.0, 1.0),
+    ('e', 1.3, 2, 0.0, 2.0), ('d', 1.1, 2, 1.0, 2.0), ('c', 2.5, 2, 2.0, 2.0),
+    ('r', 1.4, 3, 0.0, 3.0), ('f', 1.0, 3, 1.0, 3.0), ('v', 1.7, 3, 2.0, 3.0), ('g', 1.5, 3, 1.0, 4.0)
+];
 // Each hand has four fingers with three keys for each and two extra for the index
-const RIGHT_KEYS_COUNT: usize = 4 * 3 + 2;
+const DEFAULT_RIGHT: [DefaultKey; 4 * 3 + 2] = [
+    ('/', 3.8, 0, 2.0, 9.0), (';', 1.3, 0, 1.0, 9.0), ('.', 3.5, 1, 2.0, 8.0), ('p', 2.5, 0, 0.0, 9.0), ('l', 1.2, 1, 1.0, 8.0),
+    (',', 3.0, 2, 2.0, 7.0), ('o', 1.4, 1, 0.0, 8.0), ('k', 1.1, 2, 1.0, 7.0), ('m', 1.5, 3, 2.0, 6.0), ('i', 1.3, 2, 0.0, 7.0),
+    ('j', 1.0, 3, 1.0, 6.0), ('n', 2.0, 3, 2.0, 5.0), ('u', 2.5, 3, 0.0, 6.0), ('h', 1.5, 3, 1.0, 5.0)
+];
+
+// The characters come first so the corpus tables only need to cover them
+pub const CHAR_KEYS_COUNT: usize = 98;
+pub const NAMED_KEYS_COUNT: usize = 22;
+pub const OUT_KEYS_COUNT: usize = CHAR_KEYS_COUNT + NAMED_KEYS_COUNT;
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct HandKey {
+    pub key: char,
+    pub cost: f64,
+    // Keys on the same finger get the doubled cost when pressed one after the other
+    pub finger: u32,
+
+    // Only used by the cost models that care about where keys are, older files don't have them
+    #[serde(default)]
+    pub row: f64,
+    #[serde(default)]
+    pub column: f64
+}
+
+impl HandKey {
+    pub fn distance(&self, other: &Self) -> f64 {
+        (self.row - other.row).hypot(self.column - other.column)
+    }
+}
 
-pub const IN_KEYS_COUNT: usize = LEFT_KEYS_COUNT * RIGHT_KEYS_COUNT;
-pub const OUT_KEYS_COUNT: usize = 98;
+#[derive(Clone, Serialize, Deserialize)]
+pub struct KeyGeometry {
+    // Chords of two keys on one hand (on different fingers) with nothing on the other
+    #[serde(default)]
+    pub same_hand: bool,
+    // Chords of two keys on one hand and one on the other
+    #[serde(default)]
+    pub three_keys: bool,
 
-pub const LEFT_KEYS: [char; LEFT_KEYS_COUNT] =
-    ['q', 'a', 'z', 'w', 's', 'x', 'e', 'd', 'c', 'r', 'f', 'v', 'g'];
-const LEFT_COST: [f64; LEFT_KEYS_COUNT] =
-    [2.3, 1.3, 2.5, 1.4, 1.2, 3.5, 1.3, 1.1, 2.5, 1.4, 1.0, 1.7, 1.5];
-const LEFT_MASK: [u32; LEFT_KEYS_COUNT] =
-    [ 0,   0,   0,   1,   1,   1,   2,   2,   2,   3,   3,   3,   3 ];
+    pub left: Vec<HandKey>,
+    pub right: Vec<HandKey>
+}
 
-pub const RIGHT_KEYS: [char; RIGHT_KEYS_COUNT] =
-    ['/', ';', '.', 'p', 'l', ',', 'o', 'k', 'm', 'i', 'j', 'n', 'u', 'h'];
-const RIGHT_COST: [f64; RIGHT_KEYS_COUNT] =
-    [3.8, 1.3, 3.5, 2.5, 1.2, 3.0, 1.4, 1.1, 1.5, 1.3, 1.0, 2.0, 2.5, 1.5];
-const RIGHT_MASK: [u32; RIGHT_KEYS_COUNT] =
-    [ 0,   0,   1,   0,   1,   2,   1,   2,   3,   2,   3,   3,   3,   3 ];
+impl KeyGeometry {
+    pub fn load_path(path: impl AsRef<Path>) -> io::Result<Self> {
+        let text = fs::read_to_string(path)?;
+        let geometry: Self = toml::from_str(&text).map_err(|err| invalid(err.to_string()))?;
+        geometry.validate()?;
 
-// Maybe there is a better way
-pub const IN_KEYS: [InputKey; IN_KEYS_COUNT] = const {
-    let mut ret: [InputKey; IN_KEYS_COUNT] = [InputKey::new(0, 0); IN_KEYS_COUNT];
+        Ok(geometry)
+    }
 
-    let mut value = 0;
-    while value < IN_KEYS_COUNT {
-        ret[value] = InputKey::new(value / RIGHT_KEYS_COUNT, value % RIGHT_KEYS_COUNT);
-        value += 1;
+    pub fn save_path(&self, path: impl AsRef<Path>) -> io::Result<()> {
+        let text = toml::to_string(self).map_err(|err| invalid(err.to_string()))?;
+        fs::write(path, text)
     }
 
-    ret
-};
+    pub fn validate(&self) -> io::Result<()> {
+        // Most chords need a key from each hand
+        if self.left.is_empty() || self.right.is_empty() {
+            return Err(invalid("Each hand needs at least one key".to_owned()));
+        }
+
+        if self.chord_count() < OUT_KEYS_COUNT {
+            return Err(invalid(format!("Only {} chords for {} outputs", self.chord_count(), OUT_KEYS_COUNT)));
+        }
+
+        let mut seen = vec![];
+        for key in self.left.iter().chain(self.right.iter()) {
+            if seen.contains(&key.key) {
+                return Err(invalid(format!("Key {:?} is used more than once", key.key)));
+            }
+
+            if !(key.cost.is_finite() && key.cost >= 0.0) {
+                return Err(invalid(format!("Invalid cost for key {:?}", key.key)));
+            }
+
+            // InputKey keeps the fingers of each hand as bits
+            if key.finger >= u32::BITS {
+                return Err(invalid(format!("Invalid finger for key {:?}", key.key)));
+            }
+
+            seen.push(key.key);
+        }
+
+        Ok(())
+    }
+
+    pub fn chord_count(&self) -> usize {
+        let (left, right) = (self.left.len(), self.right.len());
+        let (left_pairs, right_pairs) = (self.hand_pairs(true).len(), self.hand_pairs(false).len());
+
+        let mut count = left * right;
+        if self.same_hand {
+            count += left_pairs + right_pairs;
+        }
+
+        if self.three_keys {
+            count += left_pairs * right + left * right_pairs;
+        }
+
+        count
+    }
+
+    fn hand(&self, left: bool) -> &[HandKey] {
+        if left { &self.left } else { &self.right }
+    }
+
+    // Every two keys of a hand that can be pressed together, only when some chord uses them
+    fn hand_pairs(&self, left: bool) -> Vec<(usize, usize)> {
+        if !(self.same_hand || self.three_keys) {
+            return vec![];
+        }
+
+        let hand = self.hand(left);
+        (0..hand.len())
+            .flat_map(|first| (first + 1..hand.len()).map(move |second| (first, second)))
+            .filter(|(first, second)| hand[*first].finger != hand[*second].finger)
+            .collect()
+    }
+
+    // Single keys and then pairs of keys
+    pub fn part_count(&self, left: bool) -> usize {
+        self.hand(left).len() + self.hand_pairs(left).len()
+    }
+
+    // What one hand presses for a chord: the index of a single key, a pair of keys after those or NO_KEY for nothing
+    pub fn part_keys(&self, left: bool, part: usize) -> Vec<&HandKey> {
+        let hand = self.hand(left);
+        if part < hand.len() {
+            return vec![&hand[part]];
+        }
+
+        match self.hand_pairs(left).get(part - hand.len()) {
+            Some((first, second)) => vec![&hand[*first], &hand[*second]],
+            None => vec![]
+        }
+    }
+
+    pub fn pair_part(&self, left: bool, first: usize, second: usize) -> Option<usize> {
+        let pair = (first.min(second), first.max(second));
+        self.hand_pairs(left).iter().position(|value| *value == pair).map(|position| self.hand(left).len() + position)
+    }
+
+    // The part for the keys written out, nothing is NO_KEY
+    pub fn find_part(&self, left: bool, keys: &str) -> Option<usize> {
+        let index = |key: char| self.hand(left).iter().position(|curr| curr.key == key);
+
+        let mut chars = keys.chars();
+        match (chars.next(), chars.next(), chars.next()) {
+            (None, _, _) => Some(NO_KEY),
+            (Some(key), None, _) => index(key),
+            (Some(first), Some(second), None) => self.pair_part(left, index(first)?, index(second)?),
+            _ => None
+        }
+    }
+
+    pub fn left_index(&self, key: char) -> Option<usize> {
+        self.left.iter().position(|curr| curr.key == key)
+    }
+
+    pub fn right_index(&self, key: char) -> Option<usize> {
+        self.right.iter().position(|curr| curr.key == key)
+    }
+
+    // Every chord in order, this is what the optimizer starts from
+    // The ones with a key on each hand come first so they are what the outputs start on
+    pub fn in_keys(&self) -> Vec<InputKey> {
+        let (left, right) = (self.left.len(), self.right.len());
+        let left_pairs = left..(left + self.hand_pairs(true).len());
+        let right_pairs = right..(right + self.hand_pairs(false).len());
+
+        let mut chords: Vec<(usize, usize)> = (0..left).flat_map(|first| (0..right).map(move |second| (first, second))).collect();
+        if self.same_hand {
+            chords.extend(left_pairs.clone().map(|part| (part, NO_KEY)));
+            chords.extend(right_pairs.clone().map(|part| (NO_KEY, part)));
+        }
+
+        if self.three_keys {
+            chords.extend(left_pairs.flat_map(|part| (0..right).map(move |second| (part, second))));
+            chords.extend((0..left).flat_map(|first| right_pairs.clone().map(move |part| (first, part))));
+        }
+
+        chords.into_iter().map(|(first, second)| InputKey::new(self, first, second)).collect()
+    }
+
+    pub fn has_chord(&self, left: usize, right: usize) -> bool {
+        match (self.part_keys(true, left).len(), self.part_keys(false, right).len()) {
+            (1, 1) => true,
+            (2, 0) | (0, 2) => self.same_hand,
+            (2, 1) | (1, 2) => self.three_keys,
+            _ => false
+        }
+    }
+}
+
+impl Default for KeyGeometry {
+    fn default() -> Self {
+        let convert = |(key, cost, finger, row, column): &DefaultKey| HandKey { key: *key, cost: *cost, finger: *finger, row: *row, column: *column };
+
+        Self { same_hand: false, three_keys: false, left: DEFAULT_LEFT.iter().map(convert).collect(), right: DEFAULT_RIGHT.iter().map(convert).collect() }
+    }
+}
+
+// Keys that don't type a character, the order is the order they come in OUT_KEYS
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum NamedKey {
+    Up, Down, Left, Right,
+    Home, End, PageUp, PageDown,
+    Delete, Escape,
+    F1, F2, F3, F4, F5, F6, F7, F8, F9, F10, F11, F12
+}
+
+impl NamedKey {
+    pub const ALL: [NamedKey; NAMED_KEYS_COUNT] = [
+        Self::Up, Self::Down, Self::Left, Self::Right,
+        Self::Home, Self::End, Self::PageUp, Self::PageDown,
+        Self::Delete, Self::Escape,
+        Self::F1, Self::F2, Self::F3, Self::F4, Self::F5, Self::F6, Self::F7, Self::F8, Self::F9, Self::F10, Self::F11, Self::F12
+    ];
+
+    // What it is called in layout files
+    pub fn name(&self) -> String {
+        format!("{self:?}")
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.iter().find(|key| key.name() == name).copied()
+    }
+
+    // HID usage ids
+    pub fn keycode(&self) -> u8 {
+        match self {
+            Self::Up => 82,
+            Self::Down => 81,
+            Self::Left => 80,
+            Self::Right => 79,
+            Self::Home => 74,
+            Self::End => 77,
+            Self::PageUp => 75,
+            Self::PageDown => 78,
+            Self::Delete => 76,
+            Self::Escape => 41,
+            // F1 to F12 are in a row
+            key => 58 + (*key as u8 - Self::F1 as u8)
+        }
+    }
+}
+
+// Something a chord can output
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum OutKey {
+    Char(char),
+    Named(NamedKey)
+}
+
+impl OutKey {
+    // Layout files use the character itself or the name of the key
+    pub fn parse(value: &str) -> Option<Self> {
+        let mut chars = value.chars();
+        if let (Some(character), None) = (chars.next(), chars.next()) {
+            return OUT_KEYS[..CHAR_KEYS_COUNT].contains(&Self::Char(character)).then_some(Self::Char(character));
+        }
+
+        NamedKey::from_name(value).map(Self::Named)
+    }
+
+    pub fn index(&self) -> Option<usize> {
+        OUT_KEYS.iter().position(|key| key == self)
+    }
+
+    // For typing text, backspace doesn't count since it never shows up in text
+    pub fn from_text(character: char) -> Option<Self> {
+        match character {
+            '\n' => Some(Self::Char('↲')),
+            '\t' => Some(Self::Char('→')),
+            '←' => None,
+            character => Self::parse(&character.to_string())
+        }
+    }
+
+    pub fn character(&self) -> Option<char> {
+        match self {
+            Self::Char(character) => Some(*character),
+            Self::Named(_) => None
+        }
+    }
+}
+
+impl fmt::Display for OutKey {
+    fn fmt(&self, format: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Char(character) => write!(format, "{character}"),
+            Self::Named(key) => write!(format, "{}", key.name())
+        }
+    }
+}
 
 // \n -> ↲ \t -> → DEL -> ←
-pub const OUT_KEYS: [char; OUT_KEYS_COUNT] =
+const CHAR_KEYS: [char; CHAR_KEYS_COUNT] =
     ['↲', '→', ' ', '!', '"', '#', '$', '%', '&', '\'', '(', ')', '*', '+', ',', '-', '.', '/', '0', '1', '2', '3', '4', '5', '6', '7', '8', '9', ':', ';', '<', '=', '>', '?', '@', 'A', 'B', 'C', 'D', 'E', 'F', 'G', 'H', 'I', 'J', 'K', 'L', 'M', 'N', 'O', 'P', 'Q', 'R', 'S', 'T', 'U', 'V', 'W', 'X', 'Y', 'Z', '[', '\\', ']', '^', '_', '`', 'a', 'b', 'c', 'd', 'e', 'f', 'g', 'h', 'i', 'j', 'k', 'l', 'm', 'n', 'o', 'p', 'q', 'r', 's', 't', 'u', 'v', 'w', 'x', 'y', 'z', '{', '|', '}', '~', '←'];
-pub const OUT_KEY_PAIR_PROBS: [f64; OUT_KEYS_COUNT * OUT_KEYS_COUNT] = include_data!("../data/code.data");
+
+pub const OUT_KEYS: [OutKey; OUT_KEYS_COUNT] = {
+    let mut keys = [OutKey::Char(' '); OUT_KEYS_COUNT];
+
+    let mut i = 0;
+    while i < CHAR_KEYS_COUNT {
+        keys[i] = OutKey::Char(CHAR_KEYS[i]);
+        i += 1;
+    }
+
+    while i < OUT_KEYS_COUNT {
+        keys[i] = OutKey::Named(NamedKey::ALL[i - CHAR_KEYS_COUNT]);
+        i += 1;
+    }
+
+    keys
+};
+
+// Only the characters, the named keys never show up in text
+pub static CHAR_PAIR_PROBS: [f64; CHAR_KEYS_COUNT * CHAR_KEYS_COUNT] = include_data!("../data/code.data");
 
 pub fn index_pair(prev: usize, curr: usize) -> usize {
     (prev * OUT_KEYS_COUNT) + curr
 }
 
-#[derive(Copy, Clone)]
+// The side of a tap or a same hand chord that isn't pressed
+pub const NO_KEY: usize = usize::MAX;
+
+#[derive(Copy, Clone, Serialize, Deserialize)]
 pub struct InputKey {
+    // Parts of KeyGeometry, usually just the index of one key
     pub left: usize,
     pub right: usize,
 
+    // Up to two keys for each hand, '\0' for the ones that aren't there
+    left_keys: [char; 2],
+    right_keys: [char; 2],
+
     cost: f64,
+    // One bit for each finger that is used
     left_mask: u32,
     right_mask: u32
 }
 
 impl InputKey {
-    pub const fn new(left: usize, right: usize) -> Self {
-        Self { left, right, cost: LEFT_COST[left] + RIGHT_COST[right], left_mask: LEFT_MASK[left], right_mask: RIGHT_MASK[right] }
+    pub fn new(geometry: &KeyGeometry, left: usize, right: usize) -> Self {
+        let (left_hand, right_hand) = (geometry.part_keys(true, left), geometry.part_keys(false, right));
+
+        let chars = |hand: &[&HandKey]| {
+            let mut keys = ['\0'; 2];
+            for (key, hand_key) in keys.iter_mut().zip(hand) {
+                *key = hand_key.key;
+            }
+
+            keys
+        };
+        let mask = |hand: &[&HandKey]| hand.iter().fold(0, |mask, key| mask | 1 << key.finger);
+
+        Self {
+            left,
+            right,
+            left_keys: chars(&left_hand),
+            right_keys: chars(&right_hand),
+            cost: left_hand.iter().chain(right_hand.iter()).map(|key| key.cost).sum(),
+            left_mask: mask(&left_hand),
+            right_mask: mask(&right_hand)
+        }
+    }
+
+    // One key pressed on its own
+    pub fn tap(geometry: &KeyGeometry, left: bool, index: usize) -> Self {
+        if left { Self::new(geometry, index, NO_KEY) } else { Self::new(geometry, NO_KEY, index) }
     }
 
-    // LEFT_KEYS_COUNT and RIGHT_KEYS_COUNT < BYTE
-    pub fn from_bytes(bytes: [u8; 2]) -> Self {
-        Self::new(bytes[0] as usize, bytes[1] as usize)
+    pub fn is_tap(&self) -> bool {
+        self.left_keys.iter().chain(self.right_keys.iter()).filter(|key| **key != '\0').count() == 1
     }
 
     pub fn compare(&self, left: usize, right: usize) -> bool {
         self.left == left && self.right == right
     }
 
-    pub fn get_cost(&self, prev: &Self) -> f64 {
-        // Maybe it should use the distance the finger has to move?
-        if (self.left_mask == prev.left_mask && self.left != prev.left) || (self.right_mask == prev.right_mask && self.right != prev.right) { self.cost * 2.0 } else { self.cost }
+    pub fn left_name(&self) -> String {
+        self.left_keys.iter().filter(|key| **key != '\0').collect()
     }
 
-    // LEFT_KEYS_COUNT and RIGHT_KEYS_COUNT < BYTE
-    pub fn as_bytes(&self) -> [u8; 2] {
-        [self.left as u8, self.right as u8]
+    pub fn right_name(&self) -> String {
+        self.right_keys.iter().filter(|key| **key != '\0').collect()
+    }
+
+    // "left:right" with either side empty for a same hand chord, a tap is just its key
+    pub fn name(&self) -> String {
+        if self.is_tap() {
+            return self.left_name() + &self.right_name();
+        }
+
+        format!("{}:{}", self.left_name(), self.right_name())
+    }
+
+    // Without anything pressed before it
+    pub fn cost(&self) -> f64 {
+        self.cost
+    }
+
+    pub fn get_cost(&self, prev: &Self) -> f64 {
+        // cost::ErgonomicModel also counts how far the finger has to move
+        if ((self.left_mask & prev.left_mask) != 0 && self.left != prev.left) || ((self.right_mask & prev.right_mask) != 0 && self.right != prev.right) { self.cost * 2.0 } else { self.cost }
     }
 }
 
 impl fmt::Display for InputKey {
     fn fmt(&self, format: &mut fmt::Formatter<'_>) -> fmt::Result {
         format.debug_struct("InputKey")
-            .field("left", &LEFT_KEYS[self.left])
-            .field("right", &RIGHT_KEYS[self.right])
+            .field("left", &self.left_name())
+            .field("right", &self.right_name())
             .finish()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{env, process};
+
+    use super::*;
+
+    #[test]
+    fn bad_geometries_are_rejected() {
+        assert!(KeyGeometry::default().validate().is_ok());
+
+        // Plenty of same hand chords, but nothing to pair with
+        let mut geometry = KeyGeometry { same_hand: true, ..KeyGeometry::default() };
+        geometry.left.clear();
+        assert!(geometry.validate().is_err());
+
+        let mut geometry = KeyGeometry::default();
+        geometry.left.truncate(5);
+        geometry.right.truncate(5);
+        assert!(geometry.validate().is_err());
+
+        let mut geometry = KeyGeometry::default();
+        geometry.right[3].key = 'q';
+        assert!(geometry.validate().is_err());
+
+        for cost in [f64::NAN, f64::INFINITY, -1.0] {
+            let mut geometry = KeyGeometry::default();
+            geometry.left[0].cost = cost;
+            assert!(geometry.validate().is_err());
+        }
+
+        let mut geometry = KeyGeometry::default();
+        geometry.right[0].finger = u32::BITS;
+        assert!(geometry.validate().is_err());
+    }
+
+    #[test]
+    fn geometry_files_load() {
+        let geometry = KeyGeometry::load_path("data/geometry.toml").unwrap();
+        assert_eq!(geometry.in_keys().iter().map(InputKey::name).collect::<Vec<String>>(), KeyGeometry::default().in_keys().iter().map(InputKey::name).collect::<Vec<String>>());
+
+        let path = env::temp_dir().join(format!("kybr-geometry-{}.toml", process::id()));
+        let mut geometry = KeyGeometry { three_keys: true, ..KeyGeometry::default() };
+        geometry.right[0].cost = 0.5;
+        geometry.save_path(&path).unwrap();
+        let loaded = KeyGeometry::load_path(&path);
+
+        // Loading checks it like anything else
+        geometry.left[1].key = 'q';
+        geometry.save_path(&path).unwrap();
+        let duplicate = KeyGeometry::load_path(&path);
+        fs::remove_file(path).unwrap();
+
+        let loaded = loaded.unwrap();
+        assert!(loaded.three_keys && !loaded.same_hand);
+        assert_eq!(loaded.right[0].cost, 0.5);
+        assert!(duplicate.is_err());
+    }
+
+    #[test]
+    fn every_chord_is_counted() {
+        for (same_hand, three_keys) in [(false, false), (true, false), (false, true), (true, true)] {
+            let geometry = KeyGeometry { same_hand, three_keys, ..KeyGeometry::default() };
+            let keys = geometry.in_keys();
+
+            assert_eq!(geometry.chord_count(), keys.len());
+            assert!(keys.iter().all(|key| geometry.has_chord(key.left, key.right)));
+        }
+    }
+}
@@ -1,18 +1,83 @@
-use std::{fs::File, io::{Error, Read, Write}, slice::from_raw_parts, time::Duration};
+use std::{collections::BTreeMap, fs::{File, OpenOptions}, str::FromStr, io::{Error, ErrorKind, Read, Write}, mem::{zeroed, ManuallyDrop}, os::{fd::{AsRawFd, FromRawFd, RawFd}, raw::{c_int, c_ulong}}, path::{Path, PathBuf}, ptr::null_mut, slice::from_raw_parts, sync::{Arc, Mutex}, thread::{self, sleep}, time::Duration};
 
 use phf::phf_map;
+use serde::Deserialize;
 
-use crate::{input::{input_event, EV_KEY}, keyboard};
-use crate::uhid::{uhid_event, uhid_event__bindgen_ty_1, uhid_event_type_UHID_CREATE2, uhid_event_type_UHID_DESTROY, uhid_event_type_UHID_INPUT2, BUS_USB};
+use crate::{discovery::is_unplugged, error::invalid, key_converter::OutKey, input::{input_event, ioctl, EV_KEY, EV_LED, EV_SYN, KEY_CNT, SYN_REPORT, _IOC_DIRSHIFT, _IOC_NRSHIFT, _IOC_READ, _IOC_SIZESHIFT, _IOC_TYPESHIFT, _IOC_WRITE}, keyboard};
+use crate::uhid::{uhid_create2_req, uhid_event, uhid_event__bindgen_ty_1, uhid_event_type_UHID_CREATE2, uhid_event_type_UHID_DESTROY, uhid_event_type_UHID_GET_REPORT, uhid_event_type_UHID_GET_REPORT_REPLY, uhid_event_type_UHID_INPUT2, uhid_event_type_UHID_OUTPUT, uhid_event_type_UHID_SET_REPORT, uhid_event_type_UHID_SET_REPORT_REPLY, uhid_report_type_UHID_INPUT_REPORT, uhid_report_type_UHID_OUTPUT_REPORT, BUS_BLUETOOTH, BUS_I2C, BUS_USB, BUS_VIRTUAL};
 
-// Maybe make the key rollover higher
-// Volume doesn't work
+// What the virtual keyboard says it is plugged into
+#[derive(Clone, Copy, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Bus {
+    Usb,
+    Bluetooth,
+    I2c,
+    Virtual
+}
+
+impl Bus {
+    fn number(self) -> u32 {
+        match self {
+            Self::Usb => BUS_USB,
+            Self::Bluetooth => BUS_BLUETOOTH,
+            Self::I2c => BUS_I2C,
+            Self::Virtual => BUS_VIRTUAL
+        }
+    }
+}
+
+// How the virtual keyboard shows up to everything else, udev rules and xkb settings can match on these
+#[derive(Clone, Deserialize)]
+#[serde(default)]
+pub struct DeviceIdentity {
+    pub name: String,
+    pub vendor: u16,
+    pub product: u16,
+    pub version: u32,
+    pub bus: Bus,
+    pub phys: String,
+    pub uniq: String
+}
+
+impl Default for DeviceIdentity {
+    fn default() -> Self {
+        Self {
+            name: "Kybr Virtual Keyboard".to_owned(),
+            vendor: 0x15D9,
+            product: 0x0A37,
+            version: 1,
+            bus: Bus::Usb,
+            phys: "kybr/input0".to_owned(),
+            uniq: String::new()
+        }
+    }
+}
 
-const NAME: [u8; 5] = [b'T', b'e', b's', b't', b'\0'];
-const DESC: [u8; 63] = [
+impl DeviceIdentity {
+    // The strings need room for the terminating zero in the create event
+    pub fn validate(&self) -> Result<(), Error> {
+        let create = uhid_create2_req::default();
+
+        for (field, value, length) in [("name", &self.name, create.name.len()), ("phys", &self.phys, create.phys.len()), ("uniq", &self.uniq, create.uniq.len())] {
+            if value.len() >= length {
+                return Err(invalid(format!("The virtual keyboard's {field} has to be shorter than {length} bytes")));
+            }
+        }
+
+        Ok(())
+    }
+}
+
+const KEYBOARD_REPORT: u8 = 1;
+const CONSUMER_REPORT: u8 = 2;
+
+// Six-key report, the layout of the boot protocol one but behind a report ID so it isn't usable as boot protocol
+const DESC: [u8; 65] = [
     0x05, 0x01,
     0x09, 0x06,
     0xA1, 0x01,
+    0x85, KEYBOARD_REPORT,
     0x05, 0x07,
     0x19, 0xE0,
     0x29, 0xE7,
@@ -44,9 +109,142 @@ const DESC: [u8; 63] = [
     0xC0
 ];
 
-const CTRL: u8 = 0b0000_0001;
-const SHIFT: u8 = 0b0000_0010;
-const ALT: u8 = 0b0000_0100;
+// The same modifiers and LEDs, then one bit for each of the first 128 keycodes so any number of keys can be down
+const NKRO_DESC: [u8; 59] = [
+    0x05, 0x01,
+    0x09, 0x06,
+    0xA1, 0x01,
+    0x85, KEYBOARD_REPORT,
+    0x05, 0x07,
+    0x19, 0xE0,
+    0x29, 0xE7,
+    0x15, 0x00,
+    0x25, 0x01,
+    0x75, 0x01,
+    0x95, 0x08,
+    0x81, 0x02,
+    0x95, 0x05,
+    0x75, 0x01,
+    0x05, 0x08,
+    0x19, 0x01,
+    0x29, 0x05,
+    0x91, 0x02,
+    0x95, 0x01,
+    0x75, 0x03,
+    0x91, 0x01,
+    0x05, 0x07,
+    0x19, 0x00,
+    0x29, 0x7F,
+    0x15, 0x00,
+    0x25, 0x01,
+    0x75, 0x01,
+    0x95, 0x80,
+    0x81, 0x02,
+    0xC0
+];
+
+// Added after either keyboard report, one consumer usage at a time
+const CONSUMER_DESC: [u8; 25] = [
+    0x05, 0x0C,
+    0x09, 0x01,
+    0xA1, 0x01,
+    0x85, CONSUMER_REPORT,
+    0x15, 0x00,
+    0x26, 0xFF, 0x03,
+    0x19, 0x00,
+    0x2A, 0xFF, 0x03,
+    0x75, 0x10,
+    0x95, 0x01,
+    0x81, 0x00,
+    0xC0
+];
+
+const SIX_KEYS: usize = 6;
+const NKRO_BYTES: usize = 16;
+// What a six-key report says when more keys are down than it has room for
+const ERROR_ROLL_OVER: u8 = 0x01;
+
+#[derive(Clone, Copy, Default)]
+pub enum Rollover {
+    #[default]
+    SixKey,
+    Nkro
+}
+
+impl FromStr for Rollover {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "six-key" => Ok(Self::SixKey),
+            "nkro" => Ok(Self::Nkro),
+            _ => Err(format!("Unknown rollover {value}"))
+        }
+    }
+}
+
+// Keys on the consumer page, they don't fit in the keyboard report
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Debug)]
+pub enum MediaKey {
+    VolumeUp, VolumeDown, Mute,
+    PlayPause, Next, Previous,
+    BrightnessUp, BrightnessDown
+}
+
+impl MediaKey {
+    pub const ALL: [MediaKey; 8] = [
+        Self::VolumeUp, Self::VolumeDown, Self::Mute,
+        Self::PlayPause, Self::Next, Self::Previous,
+        Self::BrightnessUp, Self::BrightnessDown
+    ];
+
+    // What it is called in layout files
+    pub fn name(&self) -> String {
+        format!("{self:?}")
+    }
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.iter().find(|key| key.name() == name).copied()
+    }
+
+    fn usage(&self) -> u16 {
+        match self {
+            Self::VolumeUp => 0xE9,
+            Self::VolumeDown => 0xEA,
+            Self::Mute => 0xE2,
+            Self::PlayPause => 0xCD,
+            Self::Next => 0xB5,
+            Self::Previous => 0xB6,
+            Self::BrightnessUp => 0x6F,
+            Self::BrightnessDown => 0x70
+        }
+    }
+}
+
+impl Rollover {
+    fn descriptor(&self) -> &'static [u8] {
+        match self {
+            Self::SixKey => &DESC,
+            Self::Nkro => &NKRO_DESC
+        }
+    }
+}
+
+// The LED bits in the keyboard's output report, which are also the evdev LED codes in order
+pub const NUM_LOCK: u8 = 0b0000_0001;
+pub const CAPS_LOCK: u8 = 0b0000_0010;
+pub const SCROLL_LOCK: u8 = 0b0000_0100;
+const LED_COUNT: u16 = 5;
+
+pub const LED_NAMES: [(&str, u8); 3] = [("num", NUM_LOCK), ("caps", CAPS_LOCK), ("scroll", SCROLL_LOCK)];
+
+pub const CTRL: u8 = 0b0000_0001;
+pub const SHIFT: u8 = 0b0000_0010;
+pub const ALT: u8 = 0b0000_0100;
+pub const SUPER: u8 = 0b0000_1000;
+
+// The characters the physical modifier keys read as, also what a modifier chord outputs
+pub const MODIFIER_NAMES: [(&str, char); 4] = [("ctrl", '\x07'), ("shift", '\x0E'), ("alt", '↹'), ("super", '◆')];
 
 // TODO: Merge the two following hashmaps
 // Should really be CODE_TO_KEYCODE, but I am lazy
@@ -108,11 +306,23 @@ pub const CHAR_TO_KEYCODE: phf::Map<char, u8> = phf_map! {
     '⇦' => 80,
     '⇩' => 81,
     '⇧' => 82,
-    '🔊' => 128,
-    '🔇' => 129,
+    '⎀' => 73,
     '\x07' => 224,
     '\x0E' => 225,
-    '↹' => 226
+    '↹' => 226,
+    '◆' => 227
+};
+
+// What the physical media keys read as
+pub const CHAR_TO_MEDIA: phf::Map<char, MediaKey> = phf_map! {
+    '🔊' => MediaKey::VolumeUp,
+    '🔉' => MediaKey::VolumeDown,
+    '🔇' => MediaKey::Mute,
+    '⏯' => MediaKey::PlayPause,
+    '⏭' => MediaKey::Next,
+    '⏮' => MediaKey::Previous,
+    '🔆' => MediaKey::BrightnessUp,
+    '🔅' => MediaKey::BrightnessDown
 };
 
 const CHAR_TO_KEYPRESS: phf::Map<char, keyboard::KeyPress> = phf_map! {
@@ -274,13 +484,23 @@ const CODE_TO_CHAR: phf::Map<u16, char> = phf_map! {
     53u16 => '/',
     56u16 => '↹',
     57u16 => ' ',
+    // Right ctrl is the same modifier as left ctrl
+    97u16 => '\x07',
     103u16 => '⇧',
+    110u16 => '⎀',
     105u16 => '⇦',
     106u16 => '⇨',
     108u16 => '⇩',
     111u16 => '\x7F',
-    114u16 => '🔇',
-    115u16 => '🔊'
+    113u16 => '🔇',
+    114u16 => '🔉',
+    115u16 => '🔊',
+    125u16 => '◆',
+    163u16 => '⏭',
+    164u16 => '⏯',
+    165u16 => '⏮',
+    224u16 => '🔅',
+    225u16 => '🔆'
 };
 
 pub const CHAR_TO_SHIFTED: phf::Map<char, char> = phf_map! {
@@ -332,15 +552,22 @@ pub const CHAR_TO_SHIFTED: phf::Map<char, char> = phf_map! {
     '.' => '>'
 };
 
+// Every key that is down, how it is sent depends on the report the device was made with
 pub struct BoardState {
-    state: [u8; 8]
+    mods: u8,
+    keys: [u8; 32]
 }
 
 impl BoardState {
-    pub const CLEAR: BoardState = BoardState { state: [0, 0, 0, 0, 0, 0, 0, 0] };
+    pub const CLEAR: BoardState = BoardState { mods: 0, keys: [0; 32] };
 
     pub fn new_single(mods: u8, key: u8) -> Self {
-        Self { state: [mods, 0, key, 0, 0, 0, 0, 0] }
+        let mut state = Self { mods, ..Self::CLEAR };
+        if key != 0 {
+            state.push_key(key);
+        }
+
+        state
     }
 
     fn get_key_mod(character: u8) -> u8 {
@@ -348,61 +575,58 @@ impl BoardState {
             224 => CTRL,
             225 => SHIFT,
             226 => ALT,
+            227 => SUPER,
             _ => 0b0000_0000
         }
     }
 
-    pub fn push_key(&mut self, key: u8) -> bool {
+    pub fn push_key(&mut self, key: u8) {
         let key_mod = Self::get_key_mod(key);
 
         if key_mod != 0b0000_0000 {
-            self.state[0] |= key_mod;
-            return true;
-        }
-
-        let mut index = 0;
-        for i in 2..7 {
-            if self.state[i] == key {
-                return true;
-            }
-
-            if index == 0 && self.state[i] == 0 {
-                index = i;
-            }
-        }
-
-        if index == 0 {
-            return false;
+            self.mods |= key_mod;
+        } else {
+            self.keys[key as usize / 8] |= 1 << (key % 8);
         }
-
-        self.state[index] = key;
-
-        true
     }
 
     pub fn pop_key(&mut self, key: u8) {
         let key_mod = Self::get_key_mod(key);
 
         if key_mod != 0b0000_0000 {
-            self.state[0] &= !key_mod;
-            return;
+            self.mods &= !key_mod;
+        } else {
+            self.keys[key as usize / 8] &= !(1 << (key % 8));
         }
+    }
 
-        for i in 2..7 {
-            if self.state[i] == key {
-                self.state[i] = 0;
-                break;
-            }
-        }
+    fn held(&self) -> impl Iterator<Item = u8> + '_ {
+        (0..=u8::MAX).filter(|key| self.keys[*key as usize / 8] & (1 << (key % 8)) != 0)
     }
 
-    pub fn to_event(&self) -> uhid_event {
+    pub fn to_event(&self, rollover: Rollover) -> uhid_event {
         let mut data = uhid_event__bindgen_ty_1::default();
 
         let input= unsafe { &mut data.input2 };
 
-        input.size = 8;
-        input.data[..8].copy_from_slice(&self.state);
+        input.data[0] = KEYBOARD_REPORT;
+        input.data[1] = self.mods;
+        match rollover {
+            Rollover::SixKey => {
+                let held: Vec<u8> = self.held().collect();
+                if held.len() > SIX_KEYS {
+                    input.data[3..3 + SIX_KEYS].fill(ERROR_ROLL_OVER);
+                } else {
+                    input.data[3..3 + held.len()].copy_from_slice(&held);
+                }
+
+                input.size = (3 + SIX_KEYS) as u16;
+            },
+            Rollover::Nkro => {
+                input.data[2..2 + NKRO_BYTES].copy_from_slice(&self.keys[..NKRO_BYTES]);
+                input.size = (2 + NKRO_BYTES) as u16;
+            }
+        }
 
         uhid_event { type_: uhid_event_type_UHID_INPUT2, u: data }
     }
@@ -440,30 +664,236 @@ impl KeyPress {
     }
 }
 
+// None for anything that isn't a modifier key
+pub fn modifier_bit(character: char) -> Option<u8> {
+    let key_mod = BoardState::get_key_mod(*CHAR_TO_KEYCODE.get(&character)?);
+
+    if key_mod == 0b0000_0000 { None } else { Some(key_mod) }
+}
+
+// Modifiers for the chorded output
+#[derive(Default)]
+pub struct Modifiers {
+    // Physical modifier keys that are down
+    held: u8,
+    // Tapped once, used up by the next output
+    one_shot: u8,
+    // Tapped twice, stays until tapped again
+    locked: u8
+}
+
+impl Modifiers {
+    pub fn hold(&mut self, bit: u8, down: bool) {
+        if down { self.held |= bit } else { self.held &= !bit }
+    }
+
+    // The keyboard they were held on went away
+    pub fn release_held(&mut self) {
+        self.held = 0;
+    }
+
+    // Off -> one shot -> locked -> off
+    pub fn tap(&mut self, bit: u8) {
+        if self.locked & bit != 0 {
+            self.locked &= !bit;
+        } else if self.one_shot & bit != 0 {
+            self.one_shot &= !bit;
+            self.locked |= bit;
+        } else {
+            self.one_shot |= bit;
+        }
+    }
+
+    pub fn active(&self) -> u8 {
+        self.held | self.one_shot | self.locked
+    }
+
+    // After an output, true if that changed anything
+    pub fn used(&mut self) -> bool {
+        let changed = self.one_shot != 0;
+        self.one_shot = 0;
+
+        changed
+    }
+}
+
+// Shared between the writer and the thread answering the kernel
+#[derive(Default)]
+struct DeviceState {
+    // What the host last set
+    leds: u8,
+    // Lit on top of the host's LEDs to show the mode
+    indicator: u8,
+    // The last input report for each id, for GET_REPORT
+    reports: BTreeMap<u8, Vec<u8>>,
+    // The physical keyboards the LEDs are shown on
+    targets: Option<Arc<Mutex<Vec<RawFd>>>>
+}
+
+impl DeviceState {
+    fn show_leds(&self) {
+        let Some(targets) = &self.targets else {
+            return;
+        };
+
+        let leds = self.leds | self.indicator;
+        if let Ok(targets) = targets.lock() {
+            for fd in targets.iter() {
+                // A keyboard without some of the LEDs or one that just went away isn't worth failing over
+                let _ = write_leds(*fd, leds);
+            }
+        }
+    }
+}
+
+// The fd belongs to the reader, it is only borrowed here
+fn write_leds(fd: RawFd, leds: u8) -> Result<(), Error> {
+    let mut file = ManuallyDrop::new(unsafe { File::from_raw_fd(fd) });
+
+    let mut events: Vec<input_event> = (0..LED_COUNT).map(|code| input_event { type_: EV_LED as u16, code, value: ((leds >> code) & 1).into(), ..input_event::default() }).collect();
+    events.push(input_event { type_: EV_SYN as u16, code: SYN_REPORT as u16, ..input_event::default() });
+
+    file.write_all(unsafe { from_raw_parts(events.as_ptr() as *const u8, events.len() * size_of::<input_event>()) })
+}
+
+fn read_event(file: &mut File) -> Result<uhid_event, Error> {
+    let mut event = uhid_event { type_: 0, u: uhid_event__bindgen_ty_1::default() };
+    file.read_exact(unsafe { &mut *(&mut event as *mut uhid_event as *mut [u8; size_of::<uhid_event>()]) })?;
+
+    Ok(event)
+}
+
+fn write_event(file: &mut File, event: &uhid_event) -> Result<(), Error> {
+    file.write_all(unsafe { from_raw_parts(event as *const uhid_event as *const u8, size_of::<uhid_event>()) } )
+}
+
+// The kernel waits on GET and SET_REPORT until they are answered, so this runs on its own thread for as long as the device is there
+fn answer_requests(mut file: File, state: Arc<Mutex<DeviceState>>) {
+    while let Ok(event) = read_event(&mut file) {
+        let Ok(mut state) = state.lock() else {
+            return;
+        };
+
+        // START, STOP, OPEN and CLOSE don't change anything, input is sent whether or not anyone is reading
+        let reply = if event.type_ == uhid_event_type_UHID_OUTPUT {
+            let output = unsafe { &event.u.output };
+            if output.rtype == uhid_report_type_UHID_OUTPUT_REPORT as u8 && output.size >= 2 && output.data[0] == KEYBOARD_REPORT {
+                state.leds = output.data[1];
+                state.show_leds();
+            }
+
+            None
+        } else if event.type_ == uhid_event_type_UHID_GET_REPORT {
+            let request = unsafe { &event.u.get_report };
+
+            let report = if request.rtype == uhid_report_type_UHID_OUTPUT_REPORT as u8 && request.rnum == KEYBOARD_REPORT {
+                Some(vec![KEYBOARD_REPORT, state.leds])
+            } else if request.rtype == uhid_report_type_UHID_INPUT_REPORT as u8 {
+                state.reports.get(&request.rnum).cloned()
+            } else {
+                None
+            };
+
+            let mut data = uhid_event__bindgen_ty_1::default();
+            let reply = unsafe { &mut data.get_report_reply };
+            reply.id = request.id;
+            match report {
+                Some(report) => {
+                    reply.data[..report.len()].copy_from_slice(&report);
+                    reply.size = report.len() as u16;
+                },
+                None => reply.err = libc::EIO as u16
+            }
+
+            Some(uhid_event { type_: uhid_event_type_UHID_GET_REPORT_REPLY, u: data })
+        } else if event.type_ == uhid_event_type_UHID_SET_REPORT {
+            let request = unsafe { &event.u.set_report };
+
+            let mut data = uhid_event__bindgen_ty_1::default();
+            let reply = unsafe { &mut data.set_report_reply };
+            reply.id = request.id;
+            if request.rtype == uhid_report_type_UHID_OUTPUT_REPORT as u8 && request.rnum == KEYBOARD_REPORT && request.size >= 2 {
+                state.leds = request.data[1];
+                state.show_leds();
+            } else {
+                reply.err = libc::EIO as u16;
+            }
+
+            Some(uhid_event { type_: uhid_event_type_UHID_SET_REPORT_REPLY, u: data })
+        } else {
+            None
+        };
+
+        if let Some(reply) = reply {
+            if write_event(&mut file, &reply).is_err() {
+                return;
+            }
+        }
+    }
+}
+
 #[allow(clippy::upper_case_acronyms)]
 pub struct HIDWriter {
-    file: File
+    file: File,
+    rollover: Rollover,
+    state: Arc<Mutex<DeviceState>>
 }
 
 impl HIDWriter {
-    pub fn open() -> Result<Self, Error> {
-        let mut uhid = Self { file: File::create("/dev/uhid")? };
+    pub fn open(rollover: Rollover, identity: &DeviceIdentity) -> Result<Self, Error> {
+        identity.validate()?;
+
+        let file = OpenOptions::new().read(true).write(true).open("/dev/uhid")?;
+        let state = Arc::new(Mutex::new(DeviceState::default()));
+        let mut uhid = Self { file, rollover, state };
+        let desc = rollover.descriptor();
 
         let mut data = uhid_event__bindgen_ty_1::default();
 
         let create= unsafe { &mut data.create2 };
 
-        create.name[..NAME.len()].copy_from_slice(&NAME);
-        create.rd_data[..DESC.len()].copy_from_slice(&DESC);
-        create.rd_size = DESC.len() as u16;
-        create.bus = BUS_USB as u16;
-        create.vendor = 0x15D9;
-        create.product = 0x0A37;
+        create.name[..identity.name.len()].copy_from_slice(identity.name.as_bytes());
+        create.phys[..identity.phys.len()].copy_from_slice(identity.phys.as_bytes());
+        create.uniq[..identity.uniq.len()].copy_from_slice(identity.uniq.as_bytes());
+        create.rd_data[..desc.len()].copy_from_slice(desc);
+        create.rd_data[desc.len()..desc.len() + CONSUMER_DESC.len()].copy_from_slice(&CONSUMER_DESC);
+        create.rd_size = (desc.len() + CONSUMER_DESC.len()) as u16;
+        create.bus = identity.bus.number() as u16;
+        create.vendor = identity.vendor.into();
+        create.product = identity.product.into();
+        create.version = identity.version;
 
         uhid.push_event(&uhid_event { type_: uhid_event_type_UHID_CREATE2, u: data })?;
+
+        let (file, state) = (uhid.file.try_clone()?, uhid.state.clone());
+        thread::spawn(move || answer_requests(file, state));
+
         Ok(uhid)
     }
 
+    // The LEDs the host sets (caps lock etc.) go to these keyboards, since they are grabbed nothing else can set them
+    pub fn mirror_leds(&self, targets: Arc<Mutex<Vec<RawFd>>>) {
+        if let Ok(mut state) = self.state.lock() {
+            state.targets = Some(targets);
+            state.show_leds();
+        }
+    }
+
+    // For keyboards that were just added to the targets
+    pub fn sync_leds(&self) {
+        if let Ok(state) = self.state.lock() {
+            state.show_leds();
+        }
+    }
+
+    // Lit on the physical keyboards along with whatever the host set, to show what mode the remapper is in
+    pub fn indicate(&self, leds: u8) {
+        if let Ok(mut state) = self.state.lock() {
+            state.indicator = leds;
+            state.show_leds();
+        }
+    }
+
     pub fn tap(&mut self, character: char) -> Result<(), Box<dyn std::error::Error>> {
         let inp = CHAR_TO_KEYPRESS.get(&character).ok_or("Invalid character")?;
 
@@ -473,14 +903,67 @@ impl HIDWriter {
         Ok(())
     }
 
+    // The mods are already down from push_mods and stay down after
+    pub fn tap_with_mods(&mut self, key: OutKey, mods: u8) -> Result<(), Box<dyn std::error::Error>> {
+        let mut inp = match key {
+            OutKey::Char(character) => *CHAR_TO_KEYPRESS.get(&character).ok_or("Invalid character")?,
+            OutKey::Named(key) => KeyPress::new(key.keycode(), &[])
+        };
+        inp.add_mod(mods);
+
+        self.push_state(&inp.to_press())?;
+        self.push_mods(mods)
+    }
+
+    // Each key is let go of before the next so repeated keys come through, the mods are let go of for the text and put back after
+    pub fn type_text(&mut self, text: &str, mods: u8) -> Result<(), Box<dyn std::error::Error>> {
+        for character in text.chars() {
+            self.tap_with_mods(OutKey::from_text(character).ok_or("Invalid character")?, 0)?;
+        }
+
+        self.push_mods(mods)
+    }
+
+    pub fn tap_media(&mut self, key: MediaKey) -> Result<(), Box<dyn std::error::Error>> {
+        self.push_media(Some(key))?;
+        self.push_media(None)
+    }
+
+    // The consumer report only has room for one, so a new one replaces whatever was down
+    pub fn push_media(&mut self, key: Option<MediaKey>) -> Result<(), Box<dyn std::error::Error>> {
+        let mut data = uhid_event__bindgen_ty_1::default();
+
+        let input= unsafe { &mut data.input2 };
+
+        input.data[0] = CONSUMER_REPORT;
+        input.data[1..3].copy_from_slice(&key.map_or(0, |key| key.usage()).to_le_bytes());
+        input.size = 3;
+
+        self.push_event(&uhid_event { type_: uhid_event_type_UHID_INPUT2, u: data })?;
+
+        Ok(())
+    }
+
+    // Just the modifiers with nothing else down, so whatever they are active for shows
+    pub fn push_mods(&mut self, mods: u8) -> Result<(), Box<dyn std::error::Error>> {
+        self.push_state(&BoardState::new_single(mods, 0))
+    }
+
     pub fn push_state(&mut self, state: &BoardState) -> Result<(), Box<dyn std::error::Error>> {
-        self.push_event(&state.to_event())?;
+        self.push_event(&state.to_event(self.rollover))?;
 
         Ok(())
     }
 
     fn push_event(&mut self, event: &uhid_event) -> Result<(), Error> {
-        self.file.write_all(unsafe { from_raw_parts(event as *const uhid_event as *const u8, size_of::<uhid_event>()) } )
+        if event.type_ == uhid_event_type_UHID_INPUT2 {
+            let input = unsafe { &event.u.input2 };
+            if let Ok(mut state) = self.state.lock() {
+                state.reports.insert(input.data[0], input.data[..input.size as usize].to_vec());
+            }
+        }
+
+        write_event(&mut self.file, event)
     }
 }
 
@@ -491,8 +974,33 @@ impl Drop for HIDWriter {
     }
 }
 
+// Bindgen skips function like macros so the evdev ioctl numbers are built here like _IOC does
+pub(crate) const fn evdev_request(direction: u32, number: u32, size: usize) -> c_ulong {
+    ((direction << _IOC_DIRSHIFT) | ((b'E' as u32) << _IOC_TYPESHIFT) | (number << _IOC_NRSHIFT) | ((size as u32) << _IOC_SIZESHIFT)) as c_ulong
+}
+
+pub(crate) const KEY_BYTES: usize = (KEY_CNT as usize).div_ceil(8);
+const EVIOCGKEY: c_ulong = evdev_request(_IOC_READ, 0x18, KEY_BYTES);
+const EVIOCGRAB: c_ulong = evdev_request(_IOC_WRITE, 0x90, size_of::<c_int>());
+const EVIOCSCLOCKID: c_ulong = evdev_request(_IOC_WRITE, 0xA0, size_of::<c_int>());
+
+// The clock the key times are on, so they can be compared with the time now
+pub fn now() -> Duration {
+    let mut time = libc::timespec { tv_sec: 0, tv_nsec: 0 };
+    unsafe { libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut time) };
+
+    Duration::new(time.tv_sec as u64, time.tv_nsec as u32)
+}
+
+// How often grab checks if every key has been let go
+const RELEASE_POLL: Duration = Duration::from_millis(10);
+// A key held longer than this is stuck or being held down on purpose, so it is grabbed anyway instead of hanging the loop
+const RELEASE_WAIT: Duration = Duration::from_secs(1);
+
 pub struct HIDReader {
-    file: File
+    file: File,
+    path: PathBuf,
+    grabbed: bool
 }
 
 pub struct KeyInput {
@@ -503,11 +1011,69 @@ pub struct KeyInput {
 
 impl HIDReader {
     pub fn open(id: &str) -> Result<Self, Error> {
-        let hid = Self { file: File::open("/dev/input/event".to_owned() + id)? };
+        Self::open_path("/dev/input/event".to_owned() + id)
+    }
+
+    pub fn open_path(path: impl AsRef<Path>) -> Result<Self, Error> {
+        let path = path.as_ref().to_owned();
+        // Writable for setting the LEDs
+        let hid = Self { file: OpenOptions::new().read(true).write(true).open(&path)?, path, grabbed: false };
+
+        // Wall clock times jump around, the timeouts are worked out from these
+        if unsafe { ioctl(hid.file.as_raw_fd(), EVIOCSCLOCKID, &libc::CLOCK_MONOTONIC as *const c_int) } < 0 {
+            return Err(Error::last_os_error());
+        }
 
         Ok(hid)
     }
 
+    // Nothing else gets events from the keyboard until the grab is released (or the reader is dropped)
+    pub fn grab(&mut self) -> Result<(), Error> {
+        // A key that is down when the grab starts never has its release seen by anyone else and gets stuck (usually the enter that started this)
+        let until = now() + RELEASE_WAIT;
+        while self.any_held()? && now() < until {
+            sleep(RELEASE_POLL);
+        }
+
+        if unsafe { ioctl(self.file.as_raw_fd(), EVIOCGRAB, 1 as c_int) } < 0 {
+            return Err(Error::last_os_error());
+        }
+
+        self.grabbed = true;
+        Ok(())
+    }
+
+    // The kernel also drops the grab when the file is closed
+    pub fn ungrab(&mut self) -> Result<(), Error> {
+        if self.grabbed {
+            if unsafe { ioctl(self.file.as_raw_fd(), EVIOCGRAB, 0 as c_int) } < 0 {
+                return Err(Error::last_os_error());
+            }
+
+            self.grabbed = false;
+        }
+
+        Ok(())
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // For polling the devices and for mirroring the LEDs to them from the writer's thread
+    pub fn raw_fd(&self) -> RawFd {
+        self.file.as_raw_fd()
+    }
+
+    fn any_held(&self) -> Result<bool, Error> {
+        let mut keys = [0u8; KEY_BYTES];
+        if unsafe { ioctl(self.file.as_raw_fd(), EVIOCGKEY, keys.as_mut_ptr()) } < 0 {
+            return Err(Error::last_os_error());
+        }
+
+        Ok(keys.iter().any(|byte| *byte != 0))
+    }
+
     pub fn read(&mut self) -> Result<Option<KeyInput>, Box<dyn std::error::Error>> {
         // This isn't packed so I don't know why it is valid to load read in raw memory, but whatever
         // That's what the info I read said to do
@@ -552,3 +1118,332 @@ impl HIDReader {
         }
     }
 }
+
+impl Drop for HIDReader {
+    fn drop(&mut self) {
+        // Closing the file releases it anyway, this just makes it happen before the drop returns
+        let _ = self.ungrab();
+    }
+}
+
+struct Device {
+    reader: HIDReader,
+    // Whatever the caller wants to know the device by
+    id: usize,
+    // Applied before the key goes anywhere, so a half of a split board can be made to look like the other side of one
+    remap: BTreeMap<char, char>,
+    // The next key from this device, held until every device has been checked for an earlier one
+    pending: Option<KeyInput>
+}
+
+// What waiting on the keyboards ended with
+pub enum Event {
+    Key(KeyInput),
+    // One of the signals given to with_signals
+    Signal(c_int),
+    // At least one keyboard was unplugged and dropped
+    Unplugged,
+    Timeout
+}
+
+// Reads from several keyboards as if they were one
+pub struct MultiReader {
+    devices: Vec<Device>,
+    // Shared so the LEDs can be set from the thread answering the kernel
+    grabbed: Arc<Mutex<Vec<RawFd>>>,
+    // A signalfd, so signals are waited on along with the keys
+    signals: Option<File>
+}
+
+impl Default for MultiReader {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MultiReader {
+    pub fn new() -> Self {
+        Self { devices: vec![], grabbed: Arc::new(Mutex::new(vec![])), signals: None }
+    }
+
+    // These signals come out of wait instead of interrupting whatever is running
+    // Threads started before this still get them, so it has to be called first
+    pub fn with_signals(mut self, signals: &[c_int]) -> Result<Self, Error> {
+        let mut set: libc::sigset_t = unsafe { zeroed() };
+        unsafe { libc::sigemptyset(&mut set) };
+        for signal in signals {
+            unsafe { libc::sigaddset(&mut set, *signal) };
+        }
+
+        let err = unsafe { libc::pthread_sigmask(libc::SIG_BLOCK, &set, null_mut()) };
+        if err != 0 {
+            return Err(Error::from_raw_os_error(err));
+        }
+
+        let fd = unsafe { libc::signalfd(-1, &set, libc::SFD_CLOEXEC) };
+        if fd < 0 {
+            return Err(Error::last_os_error());
+        }
+
+        self.signals = Some(unsafe { File::from_raw_fd(fd) });
+        Ok(self)
+    }
+
+    pub fn add(&mut self, reader: HIDReader, id: usize, remap: BTreeMap<char, char>) {
+        self.devices.push(Device { reader, id, remap, pending: None });
+        self.update_grabbed();
+    }
+
+    // Lets go of every keyboard
+    pub fn clear(&mut self) {
+        let devices = std::mem::take(&mut self.devices);
+        self.update_grabbed();
+        drop(devices);
+    }
+
+    // Ungrabbed keyboards still get read, but everything else gets their keys as well
+    pub fn set_grabbed(&mut self, grab: bool) -> Result<(), Error> {
+        for device in self.devices.iter_mut() {
+            if grab { device.reader.grab()? } else { device.reader.ungrab()? }
+        }
+
+        Ok(())
+    }
+
+    pub fn ids(&self) -> Vec<usize> {
+        self.devices.iter().map(|device| device.id).collect()
+    }
+
+    pub fn paths(&self) -> Vec<PathBuf> {
+        self.devices.iter().map(|device| device.reader.path().to_owned()).collect()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.devices.is_empty()
+    }
+
+    // The fds of every device, kept up to date as they are added and unplugged
+    pub fn grabbed(&self) -> Arc<Mutex<Vec<RawFd>>> {
+        self.grabbed.clone()
+    }
+
+    fn update_grabbed(&self) {
+        if let Ok(mut grabbed) = self.grabbed.lock() {
+            *grabbed = self.devices.iter().map(|device| device.reader.raw_fd()).collect();
+        }
+    }
+
+    // Indices of the devices without a pending key that have something to read, and whether a signal came in
+    fn ready(&self, timeout: Option<Duration>) -> Result<(Vec<usize>, bool), Error> {
+        let waiting: Vec<usize> = (0..self.devices.len()).filter(|index| self.devices[*index].pending.is_none()).collect();
+
+        let poll_fd = |fd: RawFd| libc::pollfd { fd, events: libc::POLLIN, revents: 0 };
+        let mut fds: Vec<libc::pollfd> = waiting.iter().map(|index| poll_fd(self.devices[*index].reader.raw_fd())).collect();
+        if let Some(signals) = &self.signals {
+            fds.push(poll_fd(signals.as_raw_fd()));
+        }
+
+        // Rounded up, waking up just before a deadline would only mean waiting again
+        let timeout = timeout.map_or(-1, |timeout| timeout.as_nanos().div_ceil(1_000_000).min(c_int::MAX as u128) as c_int);
+        if unsafe { libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout) } < 0 {
+            let err = Error::last_os_error();
+            // A signal that isn't one of ours, same as nothing being ready
+            if err.kind() == ErrorKind::Interrupted {
+                return Ok((vec![], false));
+            }
+
+            return Err(err);
+        }
+
+        let signal = self.signals.is_some() && fds.pop().is_some_and(|fd| fd.revents != 0);
+        let ready = waiting.into_iter().zip(fds).filter(|(_, fd)| fd.revents != 0).map(|(index, _)| index).collect();
+
+        Ok((ready, signal))
+    }
+
+    fn read_signal(&mut self) -> Result<c_int, Error> {
+        let mut info: libc::signalfd_siginfo = unsafe { zeroed() };
+        if let Some(signals) = &mut self.signals {
+            signals.read_exact(unsafe { &mut *(&mut info as *mut libc::signalfd_siginfo as *mut [u8; size_of::<libc::signalfd_siginfo>()]) })?;
+        }
+
+        Ok(info.ssi_signo as c_int)
+    }
+
+    // Keys come out in the order they were pressed across every keyboard, the deadline is on the same clock as now()
+    // Unplugged keyboards are dropped and the rest keep going, with none left only a signal or the deadline ends the wait
+    pub fn wait(&mut self, deadline: Option<Duration>) -> Result<Event, Box<dyn std::error::Error>> {
+        loop {
+            // Once something is pending only what is already waiting is read, so the earliest of those comes out first
+            let waiting = self.devices.iter().any(|device| device.pending.is_some());
+            let timeout = if waiting { Some(Duration::ZERO) } else { deadline.map(|deadline| deadline.saturating_sub(now())) };
+
+            let (ready, signal) = self.ready(timeout)?;
+            if signal {
+                return Ok(Event::Signal(self.read_signal()?));
+            }
+
+            if ready.is_empty() {
+                if let Some(res) = self.pop_earliest() {
+                    return Ok(Event::Key(res));
+                }
+
+                if deadline.is_some_and(|deadline| now() >= deadline) {
+                    return Ok(Event::Timeout);
+                }
+
+                continue;
+            }
+
+            // Backwards so removing a device doesn't move the ones still to be read
+            // The removed ones are only dropped after their fds are out of grabbed, so nothing writes to a closed fd
+            let mut unplugged = vec![];
+            for index in ready.into_iter().rev() {
+                let device = &mut self.devices[index];
+                match device.reader.read() {
+                    Ok(Some(mut res)) => {
+                        res.character = device.remap.get(&res.character).copied().unwrap_or(res.character);
+                        device.pending = Some(res);
+                    },
+                    Ok(None) => {},
+                    Err(err) if err.downcast_ref::<Error>().is_some_and(is_unplugged) => {
+                        unplugged.push(self.devices.remove(index));
+                    },
+                    Err(err) => return Err(err)
+                }
+            }
+
+            if !unplugged.is_empty() {
+                self.update_grabbed();
+                return Ok(Event::Unplugged);
+            }
+        }
+    }
+
+    fn pop_earliest(&mut self) -> Option<KeyInput> {
+        self.devices.iter_mut()
+            .filter(|device| device.pending.is_some())
+            .min_by_key(|device| device.pending.as_ref().map(|res| res.time))
+            .and_then(|device| device.pending.take())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // The report bytes and how many of them are sent
+    fn report(state: &BoardState, rollover: Rollover) -> (Vec<u8>, usize) {
+        let input = unsafe { state.to_event(rollover).u.input2 };
+        (input.data[..input.size as usize].to_vec(), input.size as usize)
+    }
+
+    #[test]
+    fn six_key_reports_list_the_keys() {
+        let mut state = BoardState::CLEAR;
+        for key in [4, 225, 5] {
+            state.push_key(key);
+        }
+
+        assert_eq!(report(&state, Rollover::SixKey), (vec![KEYBOARD_REPORT, SHIFT, 0, 4, 5, 0, 0, 0, 0], 3 + SIX_KEYS));
+
+        state.pop_key(4);
+        state.pop_key(225);
+        assert_eq!(report(&state, Rollover::SixKey).0, [KEYBOARD_REPORT, 0, 0, 5, 0, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn six_key_reports_roll_over() {
+        let mut state = BoardState::new_single(CTRL, 0);
+        for key in 4..10 {
+            state.push_key(key);
+        }
+        assert_eq!(report(&state, Rollover::SixKey).0, [KEYBOARD_REPORT, CTRL, 0, 4, 5, 6, 7, 8, 9]);
+
+        // The modifiers still go through
+        state.push_key(10);
+        assert_eq!(report(&state, Rollover::SixKey).0, [KEYBOARD_REPORT, CTRL, 0, ERROR_ROLL_OVER, ERROR_ROLL_OVER, ERROR_ROLL_OVER, ERROR_ROLL_OVER, ERROR_ROLL_OVER, ERROR_ROLL_OVER]);
+    }
+
+    #[test]
+    fn nkro_reports_are_a_bitmap() {
+        let mut state = BoardState::new_single(ALT, 4);
+        for key in 5..20 {
+            state.push_key(key);
+        }
+        state.push_key(82);
+
+        let (data, size) = report(&state, Rollover::Nkro);
+        assert_eq!(size, 2 + NKRO_BYTES);
+        assert_eq!(data[..2], [KEYBOARD_REPORT, ALT]);
+
+        let mut bitmap = [0u8; NKRO_BYTES];
+        for key in (4..20).chain([82]) {
+            bitmap[key / 8] |= 1 << (key % 8);
+        }
+        assert_eq!(data[2..], bitmap);
+    }
+
+    #[test]
+    fn tapped_modifiers_go_one_shot_then_locked_then_off() {
+        let mut modifiers = Modifiers::default();
+        modifiers.tap(SHIFT);
+        assert_eq!(modifiers.active(), SHIFT);
+        // Used up by the next output
+        assert!(modifiers.used());
+        assert_eq!(modifiers.active(), 0);
+        assert!(!modifiers.used());
+
+        modifiers.tap(SHIFT);
+        modifiers.tap(SHIFT);
+        modifiers.tap(CTRL);
+        assert_eq!(modifiers.active(), SHIFT | CTRL);
+        assert!(modifiers.used());
+        assert_eq!(modifiers.active(), SHIFT);
+        assert!(!modifiers.used());
+
+        modifiers.tap(SHIFT);
+        assert_eq!(modifiers.active(), 0);
+    }
+
+    #[test]
+    fn held_modifiers_go_with_the_tapped_ones() {
+        let mut modifiers = Modifiers::default();
+        modifiers.hold(ALT, true);
+        modifiers.tap(CTRL);
+        assert_eq!(modifiers.active(), ALT | CTRL);
+        assert!(modifiers.used());
+        assert_eq!(modifiers.active(), ALT);
+
+        modifiers.hold(SUPER, true);
+        modifiers.hold(ALT, false);
+        assert_eq!(modifiers.active(), SUPER);
+        modifiers.release_held();
+        assert_eq!(modifiers.active(), 0);
+    }
+
+    #[test]
+    fn identity_strings_fit_the_create_event() {
+        let create = uhid_create2_req::default();
+        assert!(DeviceIdentity::default().validate().is_ok());
+
+        // One byte is left for the terminating zero
+        for length in [create.name.len() - 1, create.name.len()] {
+            let identity = DeviceIdentity { name: "n".repeat(length), ..DeviceIdentity::default() };
+            assert_eq!(identity.validate().is_ok(), length < create.name.len());
+        }
+
+        assert!(DeviceIdentity { phys: "p".repeat(create.phys.len()), ..DeviceIdentity::default() }.validate().is_err());
+        assert!(DeviceIdentity { uniq: "u".repeat(create.uniq.len()), ..DeviceIdentity::default() }.validate().is_err());
+    }
+
+    #[test]
+    fn identity_buses_are_named() {
+        let identity: DeviceIdentity = toml::from_str("bus = \"bluetooth\"").unwrap();
+        assert!(matches!(identity.bus, Bus::Bluetooth));
+        assert_eq!(identity.bus.number(), BUS_BLUETOOTH);
+
+        assert!(toml::from_str::<DeviceIdentity>("bus = \"USB\"").is_err());
+        assert!(toml::from_str::<DeviceIdentity>("bus = \"parallel\"").is_err());
+    }
+}
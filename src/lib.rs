@@ -1,9 +1,16 @@
 pub mod gui;
 pub mod key_converter;
 pub mod anneal;
+pub mod bindings;
+pub mod constraints;
+pub mod corpus;
+pub mod cost;
+pub mod discovery;
 pub mod keyboard;
 pub mod remapper;
 
+mod error;
+
 #[allow(warnings)]
 mod uhid;
 #[allow(warnings)]
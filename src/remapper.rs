@@ -1,62 +1,1009 @@
-use std::{collections::VecDeque, fs::File, io::Read, path::PathBuf, time::Duration};
+use std::{collections::{BTreeMap, BTreeSet, VecDeque}, fs::File, io::{self, Read, Write}, path::Path, str::FromStr, time::Duration};
 
-use iced::futures::io;
+use serde::{Deserialize, Serialize};
 
-use crate::key_converter::{InputKey, IN_KEYS_COUNT, LEFT_KEYS, OUT_KEYS, RIGHT_KEYS};
+use crate::{bindings::Action, error::invalid, key_converter::{InputKey, KeyGeometry, OutKey, CHAR_KEYS_COUNT, NO_KEY, OUT_KEYS, OUT_KEYS_COUNT}, keyboard::{MediaKey, MODIFIER_NAMES}};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum ChordPolicy {
+    // The oldest left key goes with the oldest right key
+    #[default]
+    Fifo,
+    // A press goes with the press on the other hand that is closest in time
+    Nearest,
+    // A press goes with the oldest key on the other hand that is still held down
+    Overlap
+}
+
+impl FromStr for ChordPolicy {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "fifo" => Ok(Self::Fifo),
+            "nearest" => Ok(Self::Nearest),
+            "overlap" => Ok(Self::Overlap),
+            _ => Err(format!("Unknown chord policy {value}"))
+        }
+    }
+}
+
+// What half of a chord turns into when the other half never comes
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum Fallback {
+    #[default]
+    Drop,
+    // Typed as if there was no remapper
+    Key
+}
+
+impl FromStr for Fallback {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "drop" => Ok(Self::Drop),
+            "key" => Ok(Self::Key),
+            _ => Err(format!("Unknown fallback {value}"))
+        }
+    }
+}
+
+// Chords that do something other than type one of OUT_KEYS, these have to be chords no output uses
+#[derive(Clone, Default)]
+pub struct SpecialChords {
+    // (left, right) -> the character of the modifier key it taps (keyboard::MODIFIER_NAMES)
+    pub modifiers: BTreeMap<(usize, usize), char>,
+    // (left, right) -> text typed out one key at a time
+    pub macros: BTreeMap<(usize, usize), String>,
+    // (left, right) -> volume, playback or brightness key
+    pub media: BTreeMap<(usize, usize), MediaKey>,
+    // Physical key -> what it types when it has nothing to pair with, these aren't chords so they don't take any
+    pub taps: BTreeMap<char, OutKey>,
+    // (left, right) -> the layer it turns on
+    pub switches: BTreeMap<(usize, usize), LayerSwitch>,
+    // In name order, their chords can be anything other than a switch since they go over the main layout
+    pub layers: Vec<Layer>
+}
+
+impl SpecialChords {
+    pub fn chords(&self) -> Vec<(usize, usize)> {
+        self.modifiers.keys().chain(self.macros.keys()).chain(self.media.keys()).chain(self.switches.keys()).copied().collect()
+    }
+
+    pub fn contains(&self, left: usize, right: usize) -> bool {
+        self.modifiers.contains_key(&(left, right)) || self.macros.contains_key(&(left, right)) || self.media.contains_key(&(left, right))
+            || self.switches.contains_key(&(left, right))
+    }
+}
+
+// Indices into SpecialChords::layers
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum LayerSwitch {
+    // On for as long as the keys of the switch are held, or for the next chord if they were already let go of
+    Momentary(usize),
+    // On until the same switch is pressed again
+    Toggle(usize)
+}
+
+#[derive(Clone, Default)]
+pub struct Layer {
+    pub name: String,
+    // (left, right) -> what it types while the layer is on, every other chord does what it does in the main layout
+    pub chords: BTreeMap<(usize, usize), OutKey>
+}
+
+// What a chord does
+#[derive(Clone, PartialEq, Debug)]
+pub enum Output {
+    Key(OutKey),
+    // The character of the modifier key
+    Modifier(char),
+    Macro(String),
+    Media(MediaKey),
+    // A chord from the bindings
+    Action(Action)
+}
 
 pub struct Remapper {
-    pub params: [InputKey; IN_KEYS_COUNT],
+    pub geometry: KeyGeometry,
+    pub params: Vec<InputKey>,
+    pub special: SpecialChords,
+    // Chords from the bindings, these go over everything else
+    actions: BTreeMap<(usize, usize), Action>,
     cutoff: Duration,
+    policy: ChordPolicy,
+    fallback: Fallback,
 
     // This is a case where a linkedlist could be faster
     //  but cursor and retain are expiremental
     left_keys: VecDeque<(usize, Duration)>,
-    right_keys: VecDeque<(usize, Duration)>
+    right_keys: VecDeque<(usize, Duration)>,
+
+    // Every chording key that is down right now
+    held: Vec<char>,
+    toggled: Option<usize>,
+    // The layer and the keys of its switch that are still held
+    momentary: Option<(usize, Vec<char>)>
 }
 
 impl Remapper {
-    pub fn new(params: [InputKey; IN_KEYS_COUNT], cutoff: Duration) -> Self {
-        Remapper { params, cutoff, left_keys: VecDeque::new(), right_keys: VecDeque::new() }
+    pub fn new(geometry: KeyGeometry, params: Vec<InputKey>, cutoff: Duration) -> Self {
+        Remapper { geometry, params, special: SpecialChords::default(), actions: BTreeMap::new(), cutoff, policy: ChordPolicy::default(), fallback: Fallback::default(),
+            left_keys: VecDeque::new(), right_keys: VecDeque::new(), held: vec![], toggled: None, momentary: None }
+    }
+
+    pub fn with_policy(mut self, policy: ChordPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    pub fn with_special(mut self, special: SpecialChords) -> Self {
+        self.special = special;
+        self
     }
 
-    pub fn push_key(&mut self, key: char, time: Duration) -> Option<char> {
-        if let Some(index) = LEFT_KEYS.iter().position(|curr| *curr == key) {
-            self.left_keys.push_back((index, time));
-            self.right_keys.retain(|value| value.1 >= time - self.cutoff);
-        } else if let Some(index) = RIGHT_KEYS.iter().position(|curr| *curr == key) {
-            self.right_keys.push_back((index, time));
-            self.left_keys.retain(|value| value.1 >= time - self.cutoff);
+    pub fn with_actions(mut self, actions: BTreeMap<(usize, usize), Action>) -> Self {
+        self.actions = actions;
+        self
+    }
+
+    pub fn with_fallback(mut self, fallback: Fallback) -> Self {
+        self.fallback = fallback;
+        self
+    }
+
+    // When the oldest half chord runs out of time, on the same clock as the key times
+    // Overlap keeps them for as long as they are held so nothing waits on time
+    pub fn deadline(&self) -> Option<Duration> {
+        if self.policy == ChordPolicy::Overlap {
+            return None;
+        }
+
+        self.left_keys.iter().chain(self.right_keys.iter()).map(|value| value.1 + self.cutoff).min()
+    }
+
+    // For when a keyboard goes away, nothing is going to let go of what was held on it
+    // Overlap would wait on the half chords forever, and a momentary layer would stay on
+    pub fn release_all(&mut self) {
+        self.left_keys.clear();
+        self.right_keys.clear();
+        self.held.clear();
+        self.momentary = None;
+    }
+
+    // Takes out the half chords that can't pair with anything from now on, oldest first
+    // Called before push_key with the key's time, otherwise old ones are only dropped
+    pub fn expire(&mut self, now: Duration) -> Vec<Output> {
+        if self.policy == ChordPolicy::Overlap {
+            return vec![];
+        }
+
+        let mut expired = vec![];
+        for left in [true, false] {
+            // Each hand's keys are in the order they were pressed
+            while let Some(&(index, time)) = self.hand_keys(left).front() {
+                if time + self.cutoff >= now {
+                    break;
+                }
+
+                self.hand_keys(left).pop_front();
+                expired.push((time, self.unpaired(left, index, time)));
+            }
+        }
+
+        expired.sort_by_key(|value| value.0);
+        expired.into_iter().filter_map(|value| value.1).collect()
+    }
+
+    fn hand_keys(&mut self, left: bool) -> &mut VecDeque<(usize, Duration)> {
+        if left { &mut self.left_keys } else { &mut self.right_keys }
+    }
+
+    // A key that never got the other hand, along with the next key on its hand it can still be a same hand chord
+    // That chord wins over the next key pairing with something later, since it was already pressed in time
+    fn unpaired(&mut self, left: bool, index: usize, time: Duration) -> Option<Output> {
+        if let Some(&(next, next_time)) = self.hand_keys(left).front() {
+            let part = self.geometry.pair_part(left, index, next);
+            let chord = part.map(|part| if left { (part, NO_KEY) } else { (NO_KEY, part) });
+            let in_time = self.policy == ChordPolicy::Overlap || next_time <= time + self.cutoff;
+
+            if let Some((left_part, right_part)) = chord.filter(|(left, right)| in_time && self.is_bound(*left, *right)) {
+                self.hand_keys(left).pop_front();
+                return self.press(left_part, right_part);
+            }
+        }
+
+        let key = if left { self.geometry.left[index].key } else { self.geometry.right[index].key };
+        self.fallback(key)
+    }
+
+    fn fallback(&self, key: char) -> Option<Output> {
+        if let Some(output) = self.special.taps.get(&key) {
+            return Some(Output::Key(*output));
         }
 
-        if let (Some(left), Some(right)) = (self.left_keys.front(), self.right_keys.front()) {
-            let res = self.params.iter().position(|value| value.compare(left.0, right.0));
+        match self.fallback {
+            Fallback::Drop => None,
+            Fallback::Key => OutKey::from_text(key).map(Output::Key)
+        }
+    }
 
-            self.left_keys.pop_front();
-            self.right_keys.pop_front();
+    // Releases only matter for the overlap policy, but they should always be passed in
+    pub fn push_key(&mut self, key: char, time: Duration, down: bool) -> Option<Output> {
+        let (index, left) = if let Some(index) = self.geometry.left_index(key) {
+            (index, true)
+        } else if let Some(index) = self.geometry.right_index(key) {
+            (index, false)
+        } else {
+            return None;
+        };
 
-            Some(OUT_KEYS[res?])
+        if down {
+            self.held.push(key);
         } else {
-            None
+            self.held.retain(|value| *value != key);
+            // Letting go of the last key of a momentary switch turns its layer back off
+            if let Some((_, keys)) = &mut self.momentary {
+                let count = keys.len();
+                keys.retain(|value| *value != key);
+                if keys.is_empty() && count != 0 {
+                    self.momentary = None;
+                }
+            }
+        }
+
+        let cutoff = self.cutoff;
+        let (own, other) = if left { (&mut self.left_keys, &mut self.right_keys) } else { (&mut self.right_keys, &mut self.left_keys) };
+
+        if !down {
+            // A released key can't be part of a chord anymore
+            if self.policy == ChordPolicy::Overlap {
+                if let Some(position) = own.iter().position(|value| value.0 == index) {
+                    let (_, time) = own.remove(position)?;
+                    return self.unpaired(left, index, time);
+                }
+            }
+
+            return None;
+        }
+
+        let paired = match self.policy {
+            ChordPolicy::Fifo => {
+                own.push_back((index, time));
+                other.retain(|value| value.1 + cutoff >= time);
+
+                if other.is_empty() {
+                    None
+                } else {
+                    own.pop_front().zip(other.pop_front())
+                }
+            },
+            ChordPolicy::Nearest => {
+                other.retain(|value| value.1 + cutoff >= time);
+
+                let nearest = other.iter().enumerate()
+                    .min_by_key(|(_, value)| value.1.abs_diff(time))
+                    .map(|(position, _)| position);
+
+                if let Some(position) = nearest {
+                    other.remove(position).map(|value| ((index, time), value))
+                } else {
+                    own.push_back((index, time));
+                    None
+                }
+            },
+            ChordPolicy::Overlap => {
+                // Everything left in other is still held since releases are removed
+                if let Some(value) = other.pop_front() {
+                    Some(((index, time), value))
+                } else {
+                    own.push_back((index, time));
+                    None
+                }
+            }
+        };
+
+        let (own, other) = paired?;
+        let (left, right) = if left { (own.0, other.0) } else { (other.0, own.0) };
+
+        // A key still waiting on either hand can make it a three key chord, the doubled hand has to go down first
+        if self.geometry.three_keys {
+            for hand in [true, false] {
+                let Some(&(next, next_time)) = self.hand_keys(hand).front() else {
+                    continue;
+                };
+
+                let chord = if hand {
+                    self.geometry.pair_part(true, left, next).map(|part| (part, right))
+                } else {
+                    self.geometry.pair_part(false, right, next).map(|part| (left, part))
+                };
+                let in_time = self.policy == ChordPolicy::Overlap || next_time + cutoff >= time;
+
+                if let Some((left, right)) = chord.filter(|(left, right)| in_time && self.is_bound(*left, *right)) {
+                    self.hand_keys(hand).pop_front();
+                    return self.press(left, right);
+                }
+            }
+        }
+
+        self.press(left, right)
+    }
+
+    // The layer that is on right now, None for the main layout
+    pub fn layer(&self) -> Option<&Layer> {
+        self.momentary.as_ref().map(|value| value.0).or(self.toggled).and_then(|layer| self.special.layers.get(layer))
+    }
+
+    // The chord that types the output in the layer that is on
+    pub fn chord_for(&self, output: OutKey) -> Option<InputKey> {
+        if let Some((left, right)) = self.layer().and_then(|layer| layer.chords.iter().find(|value| *value.1 == output)).map(|value| *value.0) {
+            return Some(InputKey::new(&self.geometry, left, right));
+        }
+
+        output.index().map(|index| self.params[index])
+    }
+
+    fn is_bound(&self, left: usize, right: usize) -> bool {
+        self.special.switches.contains_key(&(left, right)) || self.lookup(left, right).is_some()
+    }
+
+    // Switches only change the layer, a momentary layer with no keys held is gone after one chord
+    fn press(&mut self, left: usize, right: usize) -> Option<Output> {
+        if let Some(action) = self.actions.get(&(left, right)) {
+            return Some(Output::Action(*action));
+        }
+
+        match self.special.switches.get(&(left, right)).copied() {
+            Some(LayerSwitch::Toggle(layer)) => {
+                self.toggled = (self.toggled != Some(layer)).then_some(layer);
+                self.momentary = None;
+                None
+            },
+            Some(LayerSwitch::Momentary(layer)) => {
+                let keys = self.geometry.part_keys(true, left).iter().chain(self.geometry.part_keys(false, right).iter())
+                    .map(|key| key.key)
+                    .filter(|key| self.held.contains(key))
+                    .collect();
+                self.momentary = Some((layer, keys));
+                None
+            },
+            None => {
+                let output = self.lookup(left, right);
+                if self.momentary.as_ref().is_some_and(|value| value.1.is_empty()) {
+                    self.momentary = None;
+                }
+
+                output
+            }
+        }
+    }
+
+    // What the chord is bound to in the layer that is on or else the main layout, if anything
+    fn lookup(&self, left: usize, right: usize) -> Option<Output> {
+        if let Some(action) = self.actions.get(&(left, right)) {
+            return Some(Output::Action(*action));
+        }
+
+        if let Some(key) = self.layer().and_then(|layer| layer.chords.get(&(left, right))) {
+            return Some(Output::Key(*key));
+        }
+
+        if let Some(modifier) = self.special.modifiers.get(&(left, right)) {
+            return Some(Output::Modifier(*modifier));
+        }
+
+        if let Some(text) = self.special.macros.get(&(left, right)) {
+            return Some(Output::Macro(text.clone()));
         }
+
+        if let Some(key) = self.special.media.get(&(left, right)) {
+            return Some(Output::Media(*key));
+        }
+
+        let res = self.params.iter().position(|value| value.compare(left, right));
+
+        OUT_KEYS.get(res?).copied().map(Output::Key)
     }
 }
 
-pub fn load_params_path(path: PathBuf) -> io::Result<[InputKey; IN_KEYS_COUNT]> {
+const LAYOUT_VERSION: u32 = 2;
+
+#[derive(Deserialize)]
+struct Header {
+    version: u32
+}
+
+// Version 1 only listed the keys, their costs and fingers came from the compiled in tables
+#[derive(Deserialize)]
+struct KeySets {
+    left: Vec<char>,
+    right: Vec<char>
+}
+
+#[derive(Deserialize)]
+struct LayoutFileV1 {
+    keys: KeySets,
+    layout: BTreeMap<String, String>
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct LayoutFile {
+    version: u32,
+    keys: KeyGeometry,
+    // Output character -> "left:right"
+    layout: BTreeMap<String, String>,
+    // Modifier name -> "left:right"
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    modifiers: BTreeMap<String, String>,
+    // Text -> "left:right"
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    macros: BTreeMap<String, String>,
+    // Media key name -> "left:right"
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    media: BTreeMap<String, String>,
+    // The key that types it on its own -> output character
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    taps: BTreeMap<String, String>,
+    // "left:right" -> the layer it turns on while held
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    momentary: BTreeMap<String, String>,
+    // "left:right" -> the layer it turns on and off
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    toggle: BTreeMap<String, String>,
+    // Layer name -> output character -> "left:right"
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    layers: BTreeMap<String, BTreeMap<String, String>>
+}
+
+// Anything that isn't a .toml layout is assumed to be the old raw format
+pub fn load_params_path(path: impl AsRef<Path>) -> io::Result<(KeyGeometry, Vec<InputKey>, SpecialChords)> {
+    let path = path.as_ref();
     let mut file = File::options().read(true).open(path)?;
-    load_params(&mut file)
+
+    if path.extension().is_some_and(|extension| extension == "toml") {
+        load_layout(&mut file)
+    } else {
+        let geometry = KeyGeometry::default();
+        let params = load_params(&mut file, &geometry)?;
+        Ok((geometry, params, SpecialChords::default()))
+    }
 }
 
-// TODO: Use this function instead of of copy and pasting this code in the binaries
-pub fn load_params(file: &mut impl Read) -> io::Result<[InputKey; IN_KEYS_COUNT]> {
-    let mut params: [InputKey; IN_KEYS_COUNT] = [InputKey::new(0, 0); IN_KEYS_COUNT];
+// Legacy format, the left and right key index of each InputKey as a byte each in OUT_KEYS order (only the characters)
+pub fn load_params(file: &mut impl Read, geometry: &KeyGeometry) -> io::Result<Vec<InputKey>> {
+    let mut params = vec![InputKey::new(geometry, 0, 0); geometry.chord_count()];
+    let mut assigned = [false; OUT_KEYS_COUNT];
     let mut buf: [u8; 2] = [0, 0];
-    for param in params.iter_mut() {
-        if file.read(&mut buf)? != buf.len() {
-            break;
+    // Every character has to be there, otherwise fill_unused would quietly make up chords for the missing ones
+    for index in 0..CHAR_KEYS_COUNT {
+        file.read_exact(&mut buf).map_err(|err| match err.kind() {
+            io::ErrorKind::UnexpectedEof => invalid(format!("Layout ends after {index} of {CHAR_KEYS_COUNT} characters")),
+            _ => err
+        })?;
+
+        if buf[0] as usize >= geometry.left.len() || buf[1] as usize >= geometry.right.len() {
+            return Err(invalid(format!("Invalid key bytes {buf:?}")));
         }
 
-        *param = InputKey::from_bytes(buf);
+        let (left, right) = (buf[0] as usize, buf[1] as usize);
+        if params.iter().zip(assigned).any(|(key, assigned)| assigned && key.compare(left, right)) {
+            return Err(invalid(format!("Key bytes {buf:?} are used more than once")));
+        }
+
+        params[index] = InputKey::new(geometry, left, right);
+        assigned[index] = true;
     }
 
+    fill_unused(&mut params, geometry, &assigned, &SpecialChords::default());
+
     Ok(params)
 }
+
+pub fn load_layout(file: &mut impl Read) -> io::Result<(KeyGeometry, Vec<InputKey>, SpecialChords)> {
+    let mut text = String::new();
+    file.read_to_string(&mut text)?;
+
+    let header: Header = toml::from_str(&text).map_err(|err| invalid(err.to_string()))?;
+    let layout = match header.version {
+        1 => {
+            let layout: LayoutFileV1 = toml::from_str(&text).map_err(|err| invalid(err.to_string()))?;
+            LayoutFile { version: 1, keys: geometry_from_key_sets(&layout.keys)?, layout: layout.layout, ..LayoutFile::default() }
+        },
+        LAYOUT_VERSION => {
+            let layout: LayoutFile = toml::from_str(&text).map_err(|err| invalid(err.to_string()))?;
+            layout.keys.validate()?;
+            layout
+        },
+        version => return Err(invalid(format!("Unsupported layout version {version}")))
+    };
+    let LayoutFile { keys: geometry, layout, modifiers, macros, media, taps, momentary, toggle, layers, .. } = layout;
+
+    // Outputs, modifiers, macros, media keys and switches all need chords of their own
+    let mut used = BTreeSet::new();
+    let mut claim = |chord: &String, name: &String| {
+        let (left, right) = parse_chord(chord, &geometry).ok_or_else(|| invalid(format!("Invalid chord {chord:?} for {name:?}")))?;
+        if !used.insert((left, right)) {
+            return Err(invalid(format!("Chord {chord:?} is used more than once")));
+        }
+
+        Ok((left, right))
+    };
+
+    let mut params = vec![InputKey::new(&geometry, 0, 0); geometry.chord_count()];
+    let mut assigned = [false; OUT_KEYS_COUNT];
+    for (output, chord) in layout.iter() {
+        let index = parse_output(output).ok_or_else(|| invalid(format!("Unknown output {output:?}")))?;
+        let (left, right) = claim(chord, output)?;
+
+        params[index] = InputKey::new(&geometry, left, right);
+        assigned[index] = true;
+    }
+
+    // Named keys were added later, layouts from before them get unused chords for them
+    if let Some(index) = assigned[..CHAR_KEYS_COUNT].iter().position(|assigned| !assigned) {
+        return Err(invalid(format!("No chord for {}", OUT_KEYS[index])));
+    }
+
+    let mut special = SpecialChords::default();
+    for (name, chord) in modifiers.iter() {
+        let modifier = MODIFIER_NAMES.iter().find(|modifier| modifier.0 == name).ok_or_else(|| invalid(format!("Unknown modifier {name:?}")))?.1;
+        let (left, right) = claim(chord, name)?;
+
+        special.modifiers.insert((left, right), modifier);
+    }
+
+    for (text, chord) in macros.iter() {
+        if text.is_empty() || text.chars().any(|character| OutKey::from_text(character).is_none()) {
+            return Err(invalid(format!("Macro {text:?} can't be typed")));
+        }
+
+        let (left, right) = claim(chord, text)?;
+        special.macros.insert((left, right), text.clone());
+    }
+
+    for (name, chord) in media.iter() {
+        let key = MediaKey::from_name(name).ok_or_else(|| invalid(format!("Unknown media key {name:?}")))?;
+        let (left, right) = claim(chord, name)?;
+
+        special.media.insert((left, right), key);
+    }
+
+    for (key, output) in taps.iter() {
+        let out_key = OutKey::parse(output).ok_or_else(|| invalid(format!("Unknown output {output:?}")))?;
+        let mut chars = key.chars();
+        let character = match (chars.next(), chars.next()) {
+            (Some(character), None) if geometry.left_index(character).is_some() || geometry.right_index(character).is_some() => character,
+            _ => return Err(invalid(format!("Invalid tap key {key:?} for {output:?}")))
+        };
+
+        special.taps.insert(character, out_key);
+    }
+
+    let layer_index = |name: &String| layers.keys().position(|layer| layer == name).ok_or_else(|| invalid(format!("Unknown layer {name:?}")));
+    for (switches, momentary) in [(&momentary, true), (&toggle, false)] {
+        for (chord, name) in switches.iter() {
+            let layer = layer_index(name)?;
+            let (left, right) = claim(chord, name)?;
+
+            special.switches.insert((left, right), if momentary { LayerSwitch::Momentary(layer) } else { LayerSwitch::Toggle(layer) });
+        }
+    }
+
+    for (name, outputs) in layers.iter() {
+        let mut layer = Layer { name: name.clone(), chords: BTreeMap::new() };
+        for (output, chord) in outputs.iter() {
+            let out_key = OutKey::parse(output).ok_or_else(|| invalid(format!("Unknown output {output:?} in layer {name:?}")))?;
+            let (left, right) = parse_chord(chord, &geometry).ok_or_else(|| invalid(format!("Invalid chord {chord:?} for {output:?} in layer {name:?}")))?;
+
+            // The switches have to work from every layer
+            if special.switches.contains_key(&(left, right)) || layer.chords.insert((left, right), out_key).is_some() {
+                return Err(invalid(format!("Chord {chord:?} is used more than once in layer {name:?}")));
+            }
+        }
+
+        special.layers.push(layer);
+    }
+
+    fill_unused(&mut params, &geometry, &assigned, &special);
+
+    Ok((geometry, params, special))
+}
+
+fn geometry_from_key_sets(keys: &KeySets) -> io::Result<KeyGeometry> {
+    let default = KeyGeometry::default();
+
+    let mut left = vec![];
+    for key in keys.left.iter() {
+        left.push(default.left[default.left_index(*key).ok_or_else(|| invalid(format!("Unknown left key {key:?}")))?].clone());
+    }
+
+    let mut right = vec![];
+    for key in keys.right.iter() {
+        right.push(default.right[default.right_index(*key).ok_or_else(|| invalid(format!("Unknown right key {key:?}")))?].clone());
+    }
+
+    let geometry = KeyGeometry { same_hand: false, three_keys: false, left, right };
+    geometry.validate()?;
+
+    Ok(geometry)
+}
+
+pub(crate) fn parse_output(output: &str) -> Option<usize> {
+    OutKey::parse(output)?.index()
+}
+
+// Up to two keys on each side of the colon, whatever the geometry has chords for
+pub(crate) fn parse_chord(chord: &str, geometry: &KeyGeometry) -> Option<(usize, usize)> {
+    let (left, right) = chord.split_once(':')?;
+    let (left, right) = (geometry.find_part(true, left)?, geometry.find_part(false, right)?);
+
+    geometry.has_chord(left, right).then_some((left, right))
+}
+
+// Gives the outputs that aren't assigned the chords nothing else uses and puts the rest after the outputs
+//  so the params stay a permutation of every chord
+fn fill_unused(params: &mut [InputKey], geometry: &KeyGeometry, assigned: &[bool], special: &SpecialChords) {
+    let used: Vec<InputKey> = params[..OUT_KEYS_COUNT].iter().zip(assigned).filter(|(_, assigned)| **assigned).map(|(key, _)| *key).collect();
+
+    // Modifier, macro and media chords go last so an unassigned output doesn't take one
+    let (mut free, taken): (Vec<InputKey>, Vec<InputKey>) = geometry.in_keys().into_iter()
+        .filter(|key| !used.iter().any(|value| value.compare(key.left, key.right)))
+        .partition(|key| !special.contains(key.left, key.right));
+    free.extend(taken);
+
+    let slots = (0..OUT_KEYS_COUNT).filter(|index| !assigned[*index]).chain(OUT_KEYS_COUNT..params.len());
+    for (slot, key) in slots.zip(free) {
+        params[slot] = key;
+    }
+}
+
+pub fn save_layout_path(path: impl AsRef<Path>, geometry: &KeyGeometry, params: &[InputKey], special: &SpecialChords) -> io::Result<()> {
+    let mut file = File::create(path)?;
+    save_layout(&mut file, geometry, params, special)
+}
+
+pub fn save_layout(file: &mut impl Write, geometry: &KeyGeometry, params: &[InputKey], special: &SpecialChords) -> io::Result<()> {
+    let switch_names = |momentary: bool| special.switches.iter()
+        .filter(|(_, switch)| matches!(switch, LayerSwitch::Momentary(_)) == momentary)
+        .map(|((left, right), switch)| {
+            let (LayerSwitch::Momentary(layer) | LayerSwitch::Toggle(layer)) = switch;
+            (InputKey::new(geometry, *left, *right).name(), special.layers[*layer].name.clone())
+        })
+        .collect();
+    let modifier_name = |modifier: &char| MODIFIER_NAMES.iter().find(|name| name.1 == *modifier).map_or_else(|| modifier.to_string(), |name| name.0.to_owned());
+
+    let layout = LayoutFile {
+        version: LAYOUT_VERSION,
+        keys: geometry.clone(),
+        layout: OUT_KEYS.iter().zip(params.iter()).map(|(output, key)| (output.to_string(), key.name())).collect(),
+        modifiers: special.modifiers.iter().map(|((left, right), modifier)| (modifier_name(modifier), InputKey::new(geometry, *left, *right).name())).collect(),
+        macros: special.macros.iter().map(|((left, right), text)| (text.clone(), InputKey::new(geometry, *left, *right).name())).collect(),
+        media: special.media.iter().map(|((left, right), key)| (key.name(), InputKey::new(geometry, *left, *right).name())).collect(),
+        taps: special.taps.iter().map(|(key, output)| (key.to_string(), output.to_string())).collect(),
+        momentary: switch_names(true),
+        toggle: switch_names(false),
+        layers: special.layers.iter()
+            .map(|layer| (layer.name.clone(), layer.chords.iter().map(|((left, right), output)| (output.to_string(), InputKey::new(geometry, *left, *right).name())).collect()))
+            .collect()
+    };
+
+    let text = toml::to_string(&layout).map_err(|err| invalid(err.to_string()))?;
+    file.write_all(text.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::key_converter::{HandKey, NamedKey};
+
+    use super::*;
+
+    fn names(params: &[InputKey]) -> Vec<String> {
+        params.iter().map(InputKey::name).collect()
+    }
+
+    // Every output in chord order so what a chord types can be worked out from the keys
+    fn remapper(policy: ChordPolicy) -> Remapper {
+        let geometry = KeyGeometry::default();
+        let params = geometry.in_keys();
+        Remapper::new(geometry, params, Duration::from_millis(200)).with_policy(policy)
+    }
+
+    fn typed(remapper: &Remapper, left: char, right: char) -> Output {
+        let (left, right) = (remapper.geometry.left_index(left).unwrap(), remapper.geometry.right_index(right).unwrap());
+        Output::Key(OUT_KEYS[remapper.params.iter().position(|key| key.compare(left, right)).unwrap()])
+    }
+
+    // (key, milliseconds, down)
+    fn run(remapper: &mut Remapper, events: &[(char, u64, bool)]) -> Vec<Output> {
+        events.iter().filter_map(|(key, time, down)| remapper.push_key(*key, Duration::from_millis(*time), *down)).collect()
+    }
+
+    #[test]
+    fn rolled_keys_pair_by_policy() {
+        // a and s are rolled on the left before j and then k land on the right
+        let roll = [('a', 0, true), ('s', 50, true), ('j', 100, true), ('k', 120, true)];
+
+        let mut fifo = remapper(ChordPolicy::Fifo);
+        assert_eq!(run(&mut fifo, &roll), [typed(&fifo, 'a', 'j'), typed(&fifo, 's', 'k')]);
+
+        let mut nearest = remapper(ChordPolicy::Nearest);
+        assert_eq!(run(&mut nearest, &roll), [typed(&nearest, 's', 'j'), typed(&nearest, 'a', 'k')]);
+
+        // Nothing is let go of so it pairs the same as fifo
+        let mut overlap = remapper(ChordPolicy::Overlap);
+        assert_eq!(run(&mut overlap, &roll), [typed(&overlap, 'a', 'j'), typed(&overlap, 's', 'k')]);
+
+        // Once a is let go of only s is still held for j
+        let mut overlap = remapper(ChordPolicy::Overlap);
+        let released = [('a', 0, true), ('a', 40, false), ('s', 50, true), ('j', 100, true), ('k', 120, true)];
+        assert_eq!(run(&mut overlap, &released), [typed(&overlap, 's', 'j')]);
+    }
+
+    #[test]
+    fn overlap_drops_keys_released_before_the_other_hand() {
+        let mut remapper = remapper(ChordPolicy::Overlap);
+        assert_eq!(run(&mut remapper, &[('a', 0, true), ('a', 30, false), ('j', 100, true)]), []);
+        assert_eq!(run(&mut remapper, &[('s', 120, true)]), [typed(&remapper, 's', 'j')]);
+    }
+
+    #[test]
+    fn timed_policies_ignore_releases() {
+        for policy in [ChordPolicy::Fifo, ChordPolicy::Nearest] {
+            let mut remapper = remapper(policy);
+            assert_eq!(run(&mut remapper, &[('a', 0, true), ('a', 30, false), ('j', 100, true)]), [typed(&remapper, 'a', 'j')]);
+        }
+    }
+
+    #[test]
+    fn half_chords_expire_after_the_cutoff() {
+        let cutoff = Duration::from_millis(200);
+
+        for (fallback, expired) in [(Fallback::Drop, vec![]), (Fallback::Key, vec![Output::Key(OutKey::Char('a'))])] {
+            let mut remapper = remapper(ChordPolicy::Fifo).with_fallback(fallback);
+            assert_eq!(run(&mut remapper, &[('a', 0, true)]), []);
+
+            // At exactly the cutoff it could still be paired
+            assert_eq!(remapper.expire(cutoff), []);
+            assert_eq!(remapper.expire(cutoff + Duration::from_millis(1)), expired);
+            assert_eq!(remapper.deadline(), None);
+        }
+
+        let mut remapper = remapper(ChordPolicy::Fifo);
+        run(&mut remapper, &[('a', 0, true)]);
+        assert_eq!(remapper.expire(cutoff), []);
+        assert_eq!(run(&mut remapper, &[('j', 200, true)]), [typed(&remapper, 'a', 'j')]);
+    }
+
+    #[test]
+    fn deadline_is_the_oldest_key_plus_the_cutoff() {
+        let mut remapper = remapper(ChordPolicy::Fifo);
+        assert_eq!(remapper.deadline(), None);
+
+        run(&mut remapper, &[('s', 30, true), ('a', 50, true)]);
+        assert_eq!(remapper.deadline(), Some(Duration::from_millis(230)));
+        remapper.expire(Duration::from_millis(231));
+        assert_eq!(remapper.deadline(), Some(Duration::from_millis(250)));
+
+        // Overlap waits on releases instead
+        let mut remapper = self::remapper(ChordPolicy::Overlap);
+        run(&mut remapper, &[('a', 0, true)]);
+        assert_eq!(remapper.deadline(), None);
+    }
+
+    // Only the chords on both hands have outputs by default, so the others get macros
+    fn bound(geometry: KeyGeometry, chords: &[(&str, &str)]) -> Remapper {
+        let mut special = SpecialChords::default();
+        for (chord, text) in chords {
+            special.macros.insert(parse_chord(chord, &geometry).unwrap(), (*text).to_owned());
+        }
+
+        let params = geometry.in_keys();
+        Remapper::new(geometry, params, Duration::from_millis(200)).with_special(special)
+    }
+
+    #[test]
+    fn three_keys_take_the_waiting_key() {
+        let geometry = KeyGeometry { three_keys: true, ..KeyGeometry::default() };
+        let mut remapper = bound(geometry, &[("as:j", "asj")]);
+        assert_eq!(run(&mut remapper, &[('a', 0, true), ('s', 50, true), ('j', 100, true)]), [Output::Macro("asj".to_owned())]);
+        assert_eq!(remapper.deadline(), None);
+
+        // Past the cutoff s is too late to join
+        let mut remapper = bound(KeyGeometry { three_keys: true, ..KeyGeometry::default() }, &[("as:j", "asj")]);
+        assert_eq!(run(&mut remapper, &[('s', 0, true), ('a', 50, true), ('j', 210, true)]), [typed(&remapper, 'a', 'j')]);
+    }
+
+    #[test]
+    fn same_hand_chords_fire_on_expiry() {
+        let geometry = KeyGeometry { same_hand: true, ..KeyGeometry::default() };
+        let mut remapper = bound(geometry, &[("as:", "as")]);
+        assert_eq!(run(&mut remapper, &[('a', 0, true), ('s', 50, true)]), []);
+
+        assert_eq!(remapper.expire(Duration::from_millis(200)), []);
+        assert_eq!(remapper.expire(Duration::from_millis(201)), [Output::Macro("as".to_owned())]);
+        assert_eq!(remapper.deadline(), None);
+    }
+
+    // as: turns on a layer where d:k types 1, the switch only goes through once its keys expire
+    fn layered(switch: LayerSwitch) -> Remapper {
+        let geometry = KeyGeometry { same_hand: true, ..KeyGeometry::default() };
+        let (switch_chord, layer_chord) = (parse_chord("as:", &geometry).unwrap(), parse_chord("d:k", &geometry).unwrap());
+
+        let mut special = SpecialChords::default();
+        special.switches.insert(switch_chord, switch);
+        special.layers.push(Layer { name: "num".to_owned(), chords: [(layer_chord, OutKey::Char('1'))].into_iter().collect() });
+
+        let params = geometry.in_keys();
+        Remapper::new(geometry, params, Duration::from_millis(200)).with_special(special)
+    }
+
+    // Presses the switch at the time and lets it expire
+    fn switch(remapper: &mut Remapper, time: u64) {
+        assert_eq!(run(remapper, &[('a', time, true), ('s', time + 50, true)]), []);
+        assert_eq!(remapper.expire(Duration::from_millis(time + 201)), []);
+    }
+
+    #[test]
+    fn momentary_layers_end_on_release() {
+        let mut remapper = layered(LayerSwitch::Momentary(0));
+        switch(&mut remapper, 0);
+        assert_eq!(remapper.layer().map(|layer| layer.name.as_str()), Some("num"));
+
+        // Held for as many chords as it takes
+        assert_eq!(run(&mut remapper, &[('d', 300, true), ('k', 310, true), ('d', 320, false), ('k', 330, false)]), [Output::Key(OutKey::Char('1'))]);
+        assert_eq!(run(&mut remapper, &[('d', 400, true), ('k', 410, true)]), [Output::Key(OutKey::Char('1'))]);
+
+        // One key of the switch isn't enough to let go of it
+        assert_eq!(run(&mut remapper, &[('a', 500, false)]), []);
+        assert!(remapper.layer().is_some());
+        assert_eq!(run(&mut remapper, &[('s', 510, false)]), []);
+        assert!(remapper.layer().is_none());
+        assert_eq!(run(&mut remapper, &[('d', 600, true), ('k', 610, true)]), [typed(&remapper, 'd', 'k')]);
+    }
+
+    #[test]
+    fn unplugging_lets_go_of_everything() {
+        let mut remapper = layered(LayerSwitch::Momentary(0));
+        switch(&mut remapper, 0);
+        remapper.release_all();
+        assert!(remapper.layer().is_none());
+        assert_eq!(run(&mut remapper, &[('d', 300, true), ('k', 310, true)]), [typed(&remapper, 'd', 'k')]);
+
+        // The switch's keys coming back up later don't turn anything off
+        let mut remapper = layered(LayerSwitch::Toggle(0));
+        switch(&mut remapper, 0);
+        remapper.release_all();
+        assert!(remapper.layer().is_some());
+
+        let mut overlap = self::remapper(ChordPolicy::Overlap);
+        assert_eq!(run(&mut overlap, &[('a', 0, true)]), []);
+        overlap.release_all();
+        assert_eq!(run(&mut overlap, &[('j', 1000, true), ('s', 1010, true)]), [typed(&overlap, 's', 'j')]);
+    }
+
+    #[test]
+    fn released_momentary_layers_last_one_chord() {
+        let mut remapper = layered(LayerSwitch::Momentary(0));
+        assert_eq!(run(&mut remapper, &[('a', 0, true), ('s', 50, true), ('a', 60, false), ('s', 70, false)]), []);
+        assert_eq!(remapper.expire(Duration::from_millis(201)), []);
+        assert!(remapper.layer().is_some());
+
+        assert_eq!(run(&mut remapper, &[('d', 300, true), ('k', 310, true)]), [Output::Key(OutKey::Char('1'))]);
+        assert!(remapper.layer().is_none());
+        assert_eq!(run(&mut remapper, &[('d', 400, true), ('k', 410, true)]), [typed(&remapper, 'd', 'k')]);
+    }
+
+    #[test]
+    fn toggled_layers_end_on_the_next_press() {
+        let mut remapper = layered(LayerSwitch::Toggle(0));
+        switch(&mut remapper, 0);
+        assert_eq!(run(&mut remapper, &[('a', 260, false), ('s', 270, false)]), []);
+
+        for time in [300, 400] {
+            assert_eq!(run(&mut remapper, &[('d', time, true), ('k', time + 10, true)]), [Output::Key(OutKey::Char('1'))]);
+        }
+
+        switch(&mut remapper, 500);
+        assert!(remapper.layer().is_none());
+        assert_eq!(run(&mut remapper, &[('d', 800, true), ('k', 810, true)]), [typed(&remapper, 'd', 'k')]);
+    }
+
+    #[test]
+    fn layout_round_trips() {
+        // Fewer keys and their own costs, so the geometry has to come back from the file too
+        let mut geometry = KeyGeometry { same_hand: true, ..KeyGeometry::default() };
+        geometry.left.pop();
+        geometry.right[0].cost = 0.5;
+        let mut params = geometry.in_keys();
+        params.reverse();
+        // Special chords can only go on the ones no output has
+        let spare: Vec<(usize, usize)> = params[OUT_KEYS_COUNT..].iter().map(|key| (key.left, key.right)).collect();
+
+        let mut special = SpecialChords::default();
+        special.modifiers.insert(spare[0], '\x07');
+        special.macros.insert(spare[1], "the".to_owned());
+        special.media.insert(spare[2], MediaKey::Mute);
+        // Two keys can tap the same output
+        special.taps.insert('v', OutKey::Char('x'));
+        special.taps.insert('n', OutKey::Char('x'));
+        special.switches.insert(spare[3], LayerSwitch::Momentary(0));
+        special.switches.insert(spare[4], LayerSwitch::Toggle(1));
+        // More than one chord can switch to the same layer
+        special.switches.insert(spare[6], LayerSwitch::Momentary(0));
+        special.switches.insert(spare[7], LayerSwitch::Toggle(1));
+        special.layers.push(Layer { name: "nav".to_owned(), chords: [(spare[5], OutKey::Named(NamedKey::Up))].into_iter().collect() });
+        special.layers.push(Layer { name: "num".to_owned(), chords: [((params[0].left, params[0].right), OutKey::Char('1'))].into_iter().collect() });
+
+        let mut file = vec![];
+        save_layout(&mut file, &geometry, &params, &special).unwrap();
+        let (loaded_geometry, loaded, loaded_special) = load_layout(&mut file.as_slice()).unwrap();
+
+        assert!(loaded_geometry.same_hand);
+        assert_eq!(loaded_geometry.left.len(), geometry.left.len());
+        assert_eq!(loaded_geometry.right[0].cost, 0.5);
+        assert_eq!(names(&loaded[..OUT_KEYS_COUNT]), names(&params[..OUT_KEYS_COUNT]));
+        assert_eq!(loaded_special.modifiers, special.modifiers);
+        assert_eq!(loaded_special.macros, special.macros);
+        assert_eq!(loaded_special.media, special.media);
+        assert_eq!(loaded_special.taps, special.taps);
+        assert_eq!(loaded_special.switches, special.switches);
+        assert!(loaded_special.layers.iter().zip(special.layers.iter()).all(|(loaded, layer)| loaded.name == layer.name && loaded.chords == layer.chords));
+    }
+
+    #[test]
+    fn reused_chords_are_rejected() {
+        let geometry = KeyGeometry::default();
+        let params = geometry.in_keys();
+        let (output, spare) = ((params[0].left, params[0].right), (params[OUT_KEYS_COUNT].left, params[OUT_KEYS_COUNT].right));
+        let load = |special: &SpecialChords| {
+            let mut file = vec![];
+            save_layout(&mut file, &geometry, &params, special).unwrap();
+            load_layout(&mut file.as_slice())
+        };
+
+        let mut special = SpecialChords::default();
+        special.modifiers.insert(spare, '\x07');
+        assert!(load(&special).is_ok());
+
+        special.media.insert(output, MediaKey::Mute);
+        assert!(load(&special).is_err());
+
+        special.media.clear();
+        special.macros.insert(spare, "the".to_owned());
+        assert!(load(&special).is_err());
+    }
+
+    #[test]
+    fn version_one_layouts_import() {
+        let geometry = KeyGeometry::default();
+        let params = geometry.in_keys();
+        let layout: BTreeMap<String, String> = OUT_KEYS.iter().zip(params.iter()).map(|(output, key)| (output.to_string(), key.name())).collect();
+        let keys = |hand: &[HandKey]| hand.iter().map(|key| format!("{:?}", key.key.to_string())).collect::<Vec<String>>().join(", ");
+        let text = format!("version = 1\n\n[keys]\nleft = [{}]\nright = [{}]\n\n[layout]\n{}", keys(&geometry.left), keys(&geometry.right), toml::to_string(&layout).unwrap());
+
+        let (_, loaded, special) = load_layout(&mut text.as_bytes()).unwrap();
+        assert_eq!(names(&loaded[..OUT_KEYS_COUNT]), names(&params[..OUT_KEYS_COUNT]));
+        assert!(special.chords().is_empty());
+
+        assert!(load_layout(&mut text.replace("version = 1", "version = 9").as_bytes()).is_err());
+    }
+
+    #[test]
+    fn raw_layouts_import() {
+        let geometry = KeyGeometry::default();
+        let params = geometry.in_keys();
+        let bytes: Vec<u8> = params[..CHAR_KEYS_COUNT].iter().flat_map(|key| [key.left as u8, key.right as u8]).collect();
+
+        let loaded = load_params(&mut bytes.as_slice(), &geometry).unwrap();
+        assert_eq!(names(&loaded[..CHAR_KEYS_COUNT]), names(&params[..CHAR_KEYS_COUNT]));
+        // The named keys weren't in the raw format, they get whatever chords are left
+        assert!(loaded[CHAR_KEYS_COUNT..OUT_KEYS_COUNT].iter().all(|key| !params[..CHAR_KEYS_COUNT].iter().any(|used| used.compare(key.left, key.right))));
+
+        assert!(load_params(&mut &bytes[..bytes.len() - 2], &geometry).is_err());
+        let mut duplicate = bytes.clone();
+        duplicate.copy_within(0..2, 2);
+        assert!(load_params(&mut duplicate.as_slice(), &geometry).is_err());
+    }
+}